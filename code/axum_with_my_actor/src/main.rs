@@ -1,22 +1,251 @@
-use axum::{http::StatusCode, Extension, Json, Router};
-use shared_state_actor::SharedStateCommand;
-use tokio::sync::mpsc::Sender;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Path, Request},
+    http::request::Parts,
+    http::{header, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    Extension, Json, Router,
+};
+use shared_state_actor::{
+    ActorError, ActorHandle, CounterError, KeyedError, KeyedHandle, LimiterErrorPolicy,
+    PersistenceConfig, RateLimitConfig, RateLimitLayer, Registry, ReplicatedHandle,
+    ReplicationConfig, SupervisorConfig,
+};
+use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+/// How long a handler waits for the actor when the client doesn't ask for anything else.
+const DEFAULT_ACTOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests per second each client may make on average, unless `RATE_LIMIT_PER_SEC` is set.
+const DEFAULT_RATE_LIMIT: f64 = 100.0;
+/// Requests each client may make at once, unless `RATE_LIMIT_BURST` is set.
+const DEFAULT_RATE_BURST: u32 = 200;
+
+/// Starts the counter actor.
+///
+/// If `COUNTER_DATA_DIR` is set, the counter is persisted there and survives restarts.
+/// Otherwise it lives in memory, under a supervisor that restarts it if it panics.
+async fn start_counter() -> ActorHandle {
+    if let Some(dir) = std::env::var_os("COUNTER_DATA_DIR") {
+        return shared_state_actor::start_persistent(PersistenceConfig::new(dir))
+            .await
+            .expect("failed to open the counter journal");
+    }
+
+    let (my_actor, mut events) =
+        shared_state_actor::start_supervised(SupervisorConfig::default()).await;
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            tracing::info!(?event, "counter actor");
+        }
+    });
+    my_actor
+}
+
+/// Starts a replica of the counter shared with other instances, if
+/// `COUNTER_REPLICATION_LISTEN` is set.
+///
+/// The replica accepts peers on that address, and sends its increments to the
+/// comma-separated addresses in `COUNTER_PEERS`. `COUNTER_NODE_ID` names this instance,
+/// and defaults to the listening address. Peers aren't authenticated, so the address must
+/// only be reachable from a trusted network.
+async fn start_replica() -> Option<ReplicatedHandle> {
+    let listen = std::env::var("COUNTER_REPLICATION_LISTEN").ok()?;
+    let listen: std::net::SocketAddr = listen
+        .parse()
+        .expect("COUNTER_REPLICATION_LISTEN is not a socket address");
+    let node_id = std::env::var("COUNTER_NODE_ID").unwrap_or_else(|_| listen.to_string());
+    let peers = std::env::var("COUNTER_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| peer.trim().parse().expect("COUNTER_PEERS has a bad address"))
+        .collect();
+
+    let replica = shared_state_actor::start_replicated(ReplicationConfig {
+        peers,
+        ..ReplicationConfig::new(node_id, listen)
+    })
+    .await
+    .expect("failed to start the counter replica");
+    Some(replica.with_timeout(DEFAULT_ACTOR_TIMEOUT))
+}
+
+/// Reads the per-client rate limit from `RATE_LIMIT_PER_SEC` and `RATE_LIMIT_BURST`.
+fn rate_limit_config() -> RateLimitConfig {
+    let rate = std::env::var("RATE_LIMIT_PER_SEC")
+        .map(|rate| rate.parse().expect("RATE_LIMIT_PER_SEC is not a number"))
+        .unwrap_or(DEFAULT_RATE_LIMIT);
+    let burst = std::env::var("RATE_LIMIT_BURST")
+        .map(|burst| burst.parse().expect("RATE_LIMIT_BURST is not a number"))
+        .unwrap_or(DEFAULT_RATE_BURST);
+    RateLimitConfig::per_second(rate, burst)
+}
+
+/// Whether to rate limit clients by the `x-client-id` header, from
+/// `TRUST_CLIENT_ID_HEADER`.
+///
+/// Clients can send any id they like, and get around the limit by changing it, so only
+/// set this behind a proxy that authenticates clients and sets the header itself.
+fn trust_client_id_header() -> bool {
+    std::env::var_os("TRUST_CLIENT_ID_HEADER").is_some()
+}
+
+/// The client a request is rate limited as: the address it came from, or its
+/// `x-client-id` header if `trust_header` is set and the request has one.
+fn client_id(request: &Request, trust_header: bool) -> String {
+    if trust_header
+        && let Some(id) = request
+            .headers()
+            .get("x-client-id")
+            .and_then(|id| id.to_str().ok())
+    {
+        return id.to_string();
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip().to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// The response to a client over its rate limit.
+fn too_many_requests(retry_after: Duration) -> Response {
+    // Retry-After is in whole seconds, so round up rather than invite an early retry
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+    )
+        .into_response()
+}
+
+/// Logs to stderr, at the levels set by `RUST_LOG`, or `info` if it isn't set.
+///
+/// Set `RUST_LOG=shared_state_actor=trace` to follow every command through the actor.
+fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+}
 
 #[tokio::main]
 async fn main() {
+    init_tracing();
+
     // Start my actor here and get its handle
-    let my_actor = shared_state_actor::start().await;
+    let my_actor = start_counter().await.with_timeout(DEFAULT_ACTOR_TIMEOUT);
+    // With replication on, /json counts across every instance
+    let replica = start_replica().await;
+    // Each client gets its own budget of requests
+    let trust_header = trust_client_id_header();
+    let rate_limiter = shared_state_actor::start_rate_limiter(rate_limit_config())
+        .with_timeout(DEFAULT_ACTOR_TIMEOUT);
+    // Named counters, spread over a few actors so busy names don't block each other
+    let named_counters = shared_state_actor::start_keyed(4);
+    // Make both findable from anywhere, and visible at /actors
+    let registry = Registry::global();
+    registry
+        .register("counter", my_actor.clone())
+        .expect("the counter is registered twice");
+    named_counters
+        .register(registry, "named_counters")
+        .expect("the named counters are registered twice");
+    registry
+        .register("rate_limiter", rate_limiter.clone())
+        .expect("the rate limiter is registered twice");
+    if let Some(replica) = &replica {
+        registry
+            .register("replica", replica.clone())
+            .expect("the replica is registered twice");
+    }
+
+    // Cancelled on ctrl-c or SIGTERM. Besides stopping the server, it ends the
+    // /json/live streams, which the server would otherwise wait on forever
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
 
     let app = Router::new()
         .route("/", axum::routing::get(|| async { "Hello, World!" }))
         .route("/json", axum::routing::get(hello_json))
+        .route("/json/live", axum::routing::get(live_counter))
+        .route("/metrics", axum::routing::get(counter_metrics))
+        .route("/health", axum::routing::get(health))
+        .route("/inspect", axum::routing::get(inspect_counter))
+        .route("/actors", axum::routing::get(list_actors))
+        .route("/global", axum::routing::get(global_counter))
         .route("/json_post", axum::routing::post(receive_json))
-        .layer(Extension(my_actor)); // Add the actor here
+        .route("/counters", axum::routing::get(list_counters))
+        .route("/counters/{name}", axum::routing::post(increment_named))
+        .layer(Extension(my_actor.clone())) // Add the actor here
+        .layer(Extension(named_counters.clone()))
+        .layer(Extension(replica.clone()))
+        .layer(Extension(shutdown.clone()))
+        .layer(RateLimitLayer::new(
+            rate_limiter.clone(),
+            move |request: &Request| client_id(request, trust_header),
+            too_many_requests,
+            // A stopped rate limiter shouldn't let every client through unlimited
+            LimiterErrorPolicy::Reject,
+        ));
+
+    // Several instances on one machine need an address each
+    let addr = std::env::var("HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:3001".to_string());
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    // Connection info lets clients without an id be limited by address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.cancelled_owned())
+    .await
+    .unwrap();
+
+    // Let the actors finish their queued work (and flush to disk) before exiting
+    my_actor.shutdown().await;
+    named_counters.shutdown().await;
+    rate_limiter.shutdown().await;
+    if let Some(replica) = replica {
+        replica.shutdown().await;
+    }
+}
+
+/// Resolves when the process is asked to stop, with ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3001")
-        .await
-        .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -24,21 +253,250 @@ struct HelloJson {
     message: String,
 }
 
+/// The deadline a client set for its request with the `x-request-timeout-ms` header.
+///
+/// A header that isn't a number of milliseconds, or is too far off to represent, is
+/// refused with 400.
+struct RequestDeadline(Option<Instant>);
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestDeadline {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(header) = parts.headers.get("x-request-timeout-ms") else {
+            return Ok(RequestDeadline(None));
+        };
+        let millis: u64 = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let deadline = Instant::now()
+            .checked_add(Duration::from_millis(millis))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        Ok(RequestDeadline(Some(deadline)))
+    }
+}
+
+/// Maps a failed actor call to an HTTP status, so clients see an error instead of a wrong count.
+fn actor_status(err: ActorError) -> StatusCode {
+    match err {
+        ActorError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ActorError::Closed | ActorError::ReplyDropped | ActorError::Full => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// Maps a failed counter command to an HTTP status.
+fn counter_status(err: CounterError) -> StatusCode {
+    match err {
+        CounterError::Actor(err) => actor_status(err),
+        CounterError::Overflow { .. } | CounterError::Mismatch { .. } => StatusCode::CONFLICT,
+        CounterError::Journal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Maps a failed named counter command to an HTTP status.
+fn keyed_status(err: KeyedError) -> StatusCode {
+    match err {
+        KeyedError::Actor(err) => actor_status(err),
+        KeyedError::Overflow { .. } => StatusCode::CONFLICT,
+        KeyedError::InvalidKey => StatusCode::BAD_REQUEST,
+        KeyedError::TooManyKeys => StatusCode::INSUFFICIENT_STORAGE,
+    }
+}
+
+#[tracing::instrument(skip_all)]
 async fn hello_json(
-    Extension(my_actor): Extension<Sender<SharedStateCommand>>, // Extract the layer here
-) -> axum::Json<HelloJson> {
-    shared_state_actor::increment_counter(&my_actor).await;
-    let new_total = shared_state_actor::get_counter(&my_actor).await;
+    RequestDeadline(deadline): RequestDeadline,
+    Extension(my_actor): Extension<ActorHandle>, // Extract the layer here
+    Extension(replica): Extension<Option<ReplicatedHandle>>,
+) -> Result<axum::Json<HelloJson>, StatusCode> {
+    // Replicated, the count is the total across every instance
+    if let Some(replica) = replica {
+        let new_total = replica.increment().await.map_err(actor_status)?;
+        return Ok(axum::Json(HelloJson {
+            message: format!("Counter: {}", new_total),
+        }));
+    }
+
+    // Don't keep the actor busy for a client that has already given up
+    let my_actor = match deadline {
+        Some(deadline) => my_actor.with_deadline(deadline),
+        None => my_actor,
+    };
+
+    // One command, so the count we report is the one our own increment produced.
+    // If the actor isn't running, we can't report a meaningful count
+    let new_total = my_actor.increment_and_get().await.map_err(counter_status)?;
 
     let reply = HelloJson {
         message: format!("Counter: {}", new_total),
     };
-    axum::Json(reply)
+    Ok(axum::Json(reply))
+}
+
+/// Streams the counter to the client as server-sent events, one event per change, until
+/// the server shuts down.
+async fn live_counter(
+    Extension(my_actor): Extension<ActorHandle>,
+    Extension(shutdown): Extension<CancellationToken>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let changes = my_actor.subscribe().await.map_err(actor_status)?;
+    let events = WatchStream::new(changes)
+        .map(|count| Ok(Event::default().data(format!("Counter: {count}"))));
+    let events = futures::StreamExt::take_until(events, shutdown.cancelled_owned());
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(serde::Serialize, Debug)]
+struct CommandStats {
+    count: u64,
+    mean_latency_us: u128,
+    max_latency_us: u128,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct CounterMetrics {
+    queue_depth: usize,
+    priority_queue_depth: usize,
+    capacity: usize,
+    processed: u64,
+    rejected: u64,
+    dropped: u64,
+    expired: u64,
+    commands: BTreeMap<&'static str, CommandStats>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct CounterSnapshot {
+    value: u64,
+    uptime_secs: u64,
+    queue_depth: usize,
+    processed: u64,
+}
+
+/// Reports the counter's value along with the actor's uptime, mailbox depth and
+/// processed count.
+#[tracing::instrument(skip_all)]
+async fn inspect_counter(
+    Extension(my_actor): Extension<ActorHandle>,
+) -> Result<Json<CounterSnapshot>, StatusCode> {
+    let snapshot = my_actor.inspect().await.map_err(actor_status)?;
+    Ok(Json(CounterSnapshot {
+        value: snapshot.state,
+        uptime_secs: snapshot.uptime.as_secs(),
+        queue_depth: snapshot.metrics.queue_depth,
+        processed: snapshot.metrics.processed,
+    }))
+}
+
+/// Reports whether the counter actor is responsive.
+///
+/// The check skips ahead of queued increments, so a flood of writes doesn't fail it.
+async fn health(Extension(my_actor): Extension<ActorHandle>) -> StatusCode {
+    match my_actor.ping().await {
+        Ok(()) => StatusCode::OK,
+        Err(err) => actor_status(err),
+    }
+}
+
+/// Reports the counter actor's mailbox depth, throughput and per-command latency.
+async fn counter_metrics(Extension(my_actor): Extension<ActorHandle>) -> Json<CounterMetrics> {
+    let metrics = my_actor.metrics();
+    Json(CounterMetrics {
+        queue_depth: metrics.queue_depth,
+        priority_queue_depth: metrics.priority_queue_depth,
+        capacity: metrics.mailbox.capacity,
+        processed: metrics.processed,
+        rejected: metrics.rejected,
+        dropped: metrics.dropped,
+        expired: metrics.expired,
+        commands: metrics
+            .commands
+            .into_iter()
+            .map(|(name, command)| {
+                let stats = CommandStats {
+                    count: command.count,
+                    mean_latency_us: command.mean_latency().as_micros(),
+                    max_latency_us: command.max_latency.as_micros(),
+                };
+                (name, stats)
+            })
+            .collect(),
+    })
+}
+
+#[derive(serde::Serialize, Debug)]
+struct ActorSummary {
+    name: String,
+    kind: &'static str,
+    status: String,
+    registered_for_secs: u64,
+    queue_depth: usize,
+    processed: u64,
+}
+
+/// Lists every registered actor and its status, for diagnostics.
+async fn list_actors() -> Json<Vec<ActorSummary>> {
+    let actors = Registry::global()
+        .list()
+        .into_iter()
+        .map(|actor| ActorSummary {
+            name: actor.key,
+            kind: actor.type_name,
+            status: actor.status.to_string(),
+            registered_for_secs: actor.registered_for.as_secs(),
+            queue_depth: actor.metrics.queue_depth,
+            processed: actor.metrics.processed,
+        })
+        .collect();
+    Json(actors)
+}
+
+#[derive(serde::Serialize, Debug)]
+struct GlobalCounter {
+    node_id: String,
+    total: u64,
+    nodes: BTreeMap<String, u64>,
+}
+
+/// Reports the replicated counter's total and each instance's share of it, or 404 if
+/// replication is off.
+async fn global_counter(
+    Extension(replica): Extension<Option<ReplicatedHandle>>,
+) -> Result<Json<GlobalCounter>, StatusCode> {
+    let replica = replica.ok_or(StatusCode::NOT_FOUND)?;
+    let state = replica.state().await.map_err(actor_status)?;
+    Ok(Json(GlobalCounter {
+        node_id: replica.node_id().to_string(),
+        total: state.value(),
+        nodes: state.counts(),
+    }))
+}
+
+/// Increments the named counter and returns its new value.
+///
+/// Names can be at most 128 bytes long. Creating a counter once there are too many
+/// fails with 507 Insufficient Storage.
+async fn increment_named(
+    Path(name): Path<String>,
+    Extension(counters): Extension<KeyedHandle>,
+) -> Result<Json<u64>, StatusCode> {
+    counters.increment(&name).await.map(Json).map_err(keyed_status)
+}
+
+/// Lists every named counter.
+async fn list_counters(
+    Extension(counters): Extension<KeyedHandle>,
+) -> Result<Json<BTreeMap<String, u64>>, StatusCode> {
+    counters.list().await.map(Json).map_err(actor_status)
 }
 
 async fn receive_json(
     Json(payload): Json<HelloJson>,
 ) -> StatusCode {
-    println!("Received payload: {:?}", payload);
+    tracing::info!(?payload, "received payload");
     StatusCode::OK
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ pub mod hello_world {
 }
 
 use hello_world::greeter_client::GreeterClient;
-use hello_world::HelloRequest;
+use hello_world::{HelloRequest, WatchRequest};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,5 +17,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("RESPONSE={:?}", response);
 
+    // The first update on the stream is the counter's current value
+    let mut updates = client.watch_counter(WatchRequest {}).await?.into_inner();
+    if let Some(update) = updates.message().await? {
+        println!("COUNT={}", update.count);
+    }
+
     Ok(())
 }
\ No newline at end of file
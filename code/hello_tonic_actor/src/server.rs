@@ -1,18 +1,32 @@
-use shared_state_actor::SharedStateCommand;
-use tokio::sync::mpsc::Sender;
-use tonic::{transport::Server, Request, Response, Status};
+use shared_state_actor::{
+    ActorError, ActorHandle, CounterError, LimiterErrorPolicy, PersistenceConfig,
+    RateLimitConfig, RateLimitLayer, SupervisorConfig,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::transport::{Body, Server};
+use tonic::{Request, Response, Status};
 
 pub mod hello_world {
     tonic::include_proto!("hello");
 }
 
 use hello_world::greeter_server::{Greeter, GreeterServer};
-use hello_world::{HelloReply, HelloRequest};
+use hello_world::{CounterUpdate, HelloReply, HelloRequest, WatchRequest};
+use std::pin::Pin;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::Instrument;
 
 
 #[derive(Debug)] // I removed default
 pub struct MyGreeter {
-    my_actor: Sender<SharedStateCommand>, // Add the layer to the service struct
+    my_actor: ActorHandle, // Add the layer to the service struct
+    shutdown: CancellationToken, // Ends the WatchCounter streams when the server stops
 }
 
 #[tonic::async_trait]
@@ -21,30 +35,287 @@ impl Greeter for MyGreeter {
         &self,
         request: Request<HelloRequest>,
     ) -> Result<Response<HelloReply>, Status> {
-        println!("Got a request: {:?}", request);
+        tracing::info!(?request, "got a request");
 
-        // Use the layer
-        shared_state_actor::increment_counter(&self.my_actor).await;
-        let new_count = shared_state_actor::get_counter(&self.my_actor).await;
+        // Use the layer, giving up when the client's deadline passes
+        let my_actor = match grpc_deadline(&request) {
+            Some(deadline) => self.my_actor.with_deadline(deadline),
+            None => self.my_actor.clone(),
+        };
+        // One command, so the count we report is the one our own increment produced
+        let new_count = my_actor
+            .increment_and_get()
+            .instrument(tracing::info_span!("say_hello"))
+            .await
+            .map_err(counter_status)?;
 
         let reply = hello_world::HelloReply {
-            message: format!("Hello {}!", new_count).into(),
+            message: format!("Hello {}!", new_count),
         };
 
         Ok(Response::new(reply))
     }
+
+    type WatchCounterStream = Pin<Box<dyn Stream<Item = Result<CounterUpdate, Status>> + Send>>;
+
+    // `Status` is large, but it's the error type tonic streams are built around
+    #[allow(clippy::result_large_err)]
+    async fn watch_counter(
+        &self,
+        _request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchCounterStream>, Status> {
+        // Push every change to the client, starting with the current value, until the
+        // server shuts down. The server waits for open streams, and this one would
+        // otherwise only end once the actor has stopped, after the server
+        let changes = self.my_actor.subscribe().await.map_err(actor_status)?;
+        let updates = WatchStream::new(changes).map(|count| Ok(CounterUpdate { count }));
+        let stopped = self.shutdown.clone().cancelled_owned();
+        let updates = futures::StreamExt::take_until(updates, stopped);
+        Ok(Response::new(Box::pin(updates)))
+    }
+}
+
+/// Maps a failed actor call to a gRPC status, so clients see an error instead of a wrong count.
+fn actor_status(err: ActorError) -> Status {
+    match err {
+        ActorError::Timeout => Status::deadline_exceeded(format!("counter timed out: {err}")),
+        ActorError::Full => Status::resource_exhausted(format!("counter busy: {err}")),
+        ActorError::Closed | ActorError::ReplyDropped => {
+            Status::unavailable(format!("counter unavailable: {err}"))
+        }
+    }
+}
+
+/// Maps a failed counter command to a gRPC status.
+fn counter_status(err: CounterError) -> Status {
+    match err {
+        CounterError::Actor(err) => actor_status(err),
+        CounterError::Overflow { .. } => Status::out_of_range(err.to_string()),
+        CounterError::Mismatch { .. } => Status::failed_precondition(err.to_string()),
+        CounterError::Journal(_) => Status::internal(err.to_string()),
+    }
+}
+
+/// Reads the deadline the client sent in the `grpc-timeout` header, if any.
+///
+/// The header is an integer of up to 8 digits followed by a unit: `H`, `M`, `S`,
+/// `m` (milliseconds), `u` (microseconds) or `n` (nanoseconds). The client controls it,
+/// so a header that doesn't fit that, or a deadline too far off to represent, is ignored.
+fn grpc_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let header = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    if header.len() < 2 {
+        return None;
+    }
+    let (value, unit) = header.split_at(header.len() - 1);
+    if value.len() > 8 || !value.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(value.checked_mul(60 * 60)?),
+        "M" => Duration::from_secs(value.checked_mul(60)?),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+    Instant::now().checked_add(timeout)
+}
+
+/// Calls per second each client may make on average, unless `RATE_LIMIT_PER_SEC` is set.
+const DEFAULT_RATE_LIMIT: f64 = 100.0;
+/// Calls each client may make at once, unless `RATE_LIMIT_BURST` is set.
+const DEFAULT_RATE_BURST: u32 = 200;
+
+/// Reads the per-client rate limit from `RATE_LIMIT_PER_SEC` and `RATE_LIMIT_BURST`.
+fn rate_limit_config() -> RateLimitConfig {
+    let rate = std::env::var("RATE_LIMIT_PER_SEC")
+        .map(|rate| rate.parse().expect("RATE_LIMIT_PER_SEC is not a number"))
+        .unwrap_or(DEFAULT_RATE_LIMIT);
+    let burst = std::env::var("RATE_LIMIT_BURST")
+        .map(|burst| burst.parse().expect("RATE_LIMIT_BURST is not a number"))
+        .unwrap_or(DEFAULT_RATE_BURST);
+    RateLimitConfig::per_second(rate, burst)
+}
+
+/// Whether to rate limit clients by their `x-client-id` metadata, from
+/// `TRUST_CLIENT_ID_HEADER`.
+///
+/// Clients can send any id they like, and get around the limit by changing it, so only
+/// set this behind a proxy that authenticates clients and sets the header itself.
+fn trust_client_id_header() -> bool {
+    std::env::var_os("TRUST_CLIENT_ID_HEADER").is_some()
+}
+
+/// The client a call is rate limited as: the address it came from, or its `x-client-id`
+/// metadata if `trust_header` is set and the call has it.
+fn client_id(request: &http::Request<Body>, trust_header: bool) -> String {
+    if trust_header {
+        if let Some(id) = request
+            .headers()
+            .get("x-client-id")
+            .and_then(|id| id.to_str().ok())
+        {
+            return id.to_string();
+        }
+    }
+    request
+        .extensions()
+        .get::<TcpConnectInfo>()
+        .and_then(TcpConnectInfo::remote_addr)
+        .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+/// The response to a client over its rate limit.
+fn resource_exhausted(retry_after: Duration) -> http::Response<BoxBody> {
+    Status::resource_exhausted(format!(
+        "rate limit exceeded, retry in {}ms",
+        retry_after.as_millis()
+    ))
+    .to_http()
+}
+
+/// Starts the counter actor.
+///
+/// If `COUNTER_DATA_DIR` is set, the counter is persisted there and survives restarts.
+/// Otherwise it lives in memory, under a supervisor that restarts it if it panics.
+async fn start_counter() -> ActorHandle {
+    if let Some(dir) = std::env::var_os("COUNTER_DATA_DIR") {
+        return shared_state_actor::start_persistent(PersistenceConfig::new(dir))
+            .await
+            .expect("failed to open the counter journal");
+    }
+
+    let (my_actor, mut events) =
+        shared_state_actor::start_supervised(SupervisorConfig::default()).await;
+    tokio::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            tracing::info!(?event, "counter actor");
+        }
+    });
+    my_actor
+}
+
+/// Logs to stderr, at the levels set by `RUST_LOG`, or `info` if it isn't set.
+///
+/// Set `RUST_LOG=shared_state_actor=trace` to follow every command through the actor.
+fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let my_actor = shared_state_actor::start().await;
+    init_tracing();
+    let my_actor = start_counter().await.with_timeout(Duration::from_secs(1));
+    // Each client gets its own budget of calls
+    let trust_header = trust_client_id_header();
+    let rate_limiter = shared_state_actor::start_rate_limiter(rate_limit_config())
+        .with_timeout(Duration::from_secs(1));
     let addr = "[::1]:50051".parse()?;
-    let greeter = MyGreeter { my_actor };
+    // Cancelled on ctrl-c or SIGTERM
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+    let greeter = MyGreeter {
+        my_actor: my_actor.clone(),
+        shutdown: shutdown.clone(),
+    };
 
+    // Tonic interceptors can't wait on an actor, so the limit is checked by a layer
     Server::builder()
+        .layer(RateLimitLayer::new(
+            rate_limiter.clone(),
+            move |request: &http::Request<Body>| client_id(request, trust_header),
+            resource_exhausted,
+            // A stopped rate limiter shouldn't let every client through unlimited
+            LimiterErrorPolicy::Reject,
+        ))
         .add_service(GreeterServer::new(greeter))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown.cancelled_owned())
         .await?;
 
+    // Let the actor finish its queued work (and flush to disk) before exiting
+    my_actor.shutdown().await;
+    rate_limiter.shutdown().await;
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Resolves when the process is asked to stop, with ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_timeout(timeout: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", timeout.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_grpc_deadline_reads_the_timeout() {
+        let before = Instant::now();
+        let deadline = grpc_deadline(&request_with_timeout("5S")).unwrap();
+        assert!(deadline >= before + Duration::from_secs(5));
+        assert!(deadline <= Instant::now() + Duration::from_secs(5));
+
+        let deadline = grpc_deadline(&request_with_timeout("99999999H"));
+        assert!(deadline.is_some());
+        assert!(grpc_deadline(&Request::new(())).is_none());
+    }
+
+    #[test]
+    fn test_grpc_deadline_ignores_invalid_timeouts() {
+        // Too long for the spec, and in hours, too long to convert to seconds
+        let timeouts = [
+            "99999999999999999999H",
+            "9999999999999999H",
+            "123456789m",
+            "+5S",
+            "5",
+            "5x",
+            "S",
+        ];
+        for timeout in timeouts {
+            assert!(
+                grpc_deadline(&request_with_timeout(timeout)).is_none(),
+                "{timeout}"
+            );
+        }
+    }
+}
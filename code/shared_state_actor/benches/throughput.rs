@@ -0,0 +1,68 @@
+//! Compares the counter's throughput when it handles commands one at a time against
+//! batched mode.
+//!
+//! Run with `cargo bench -p shared_state_actor`. Many tasks hammer one counter with
+//! increments, reading it back every so often, and the commands handled per second are
+//! printed for each mailbox setting.
+
+use shared_state_actor::{MailboxConfig, start_with_mailbox};
+use std::time::{Duration, Instant};
+
+/// Tasks sending commands at the same time.
+const CLIENTS: usize = 64;
+/// Commands sent by each task.
+const COMMANDS_PER_CLIENT: usize = 5_000;
+/// Every this many commands, a task reads the counter instead of incrementing it.
+const READ_EVERY: usize = 10;
+
+/// Floods a counter with commands, returning how long it took to handle them all.
+async fn run(mailbox: MailboxConfig) -> Duration {
+    let counter = start_with_mailbox(mailbox).await;
+    let started = Instant::now();
+
+    let mut clients = tokio::task::JoinSet::new();
+    for _ in 0..CLIENTS {
+        let counter = counter.clone();
+        clients.spawn(async move {
+            for i in 0..COMMANDS_PER_CLIENT {
+                if i % READ_EVERY == 0 {
+                    counter.try_get().await.unwrap();
+                } else {
+                    counter.try_increment().await.unwrap();
+                }
+            }
+        });
+    }
+    while let Some(result) = clients.join_next().await {
+        result.unwrap();
+    }
+    // Wait for the last increments, which weren't waited for, to be handled too.
+    counter.try_get().await.unwrap();
+
+    let elapsed = started.elapsed();
+    counter.shutdown().await;
+    elapsed
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let commands = (CLIENTS * COMMANDS_PER_CLIENT) as f64;
+
+    for max_batch in [1, 8, 64] {
+        let mailbox = MailboxConfig {
+            capacity: 256,
+            max_batch,
+            ..MailboxConfig::default()
+        };
+        // Warm up, then measure.
+        runtime.block_on(run(mailbox));
+        let elapsed = runtime.block_on(run(mailbox));
+        println!(
+            "max_batch {max_batch:>3}: {:>10.0} commands/s ({elapsed:.2?})",
+            commands / elapsed.as_secs_f64()
+        );
+    }
+}
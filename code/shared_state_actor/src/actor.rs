@@ -0,0 +1,1525 @@
+//! A small, generic actor framework.
+//!
+//! An actor owns some state and processes messages one at a time, so the state never
+//! needs a lock. Implement [`Actor`] for your type, call [`spawn`] and talk to the
+//! running actor through the returned [`ActorRef`].
+//!
+//! An actor stops when every `ActorRef` to it has been dropped, or when one of them calls
+//! [`ActorRef::shutdown`]. Either way, it handles everything already in its mailbox first.
+//!
+//! The mailbox has two lanes. Messages sent through a handle made with
+//! [`ActorRef::with_priority`] go in the high-priority lane, which the actor always empties
+//! before looking at the normal one, so they aren't stuck behind a flood of bulk work.
+//!
+//! Each actor keeps [`ActorMetrics`] about its mailbox and the messages it has handled,
+//! which any handle can read with [`ActorRef::metrics`].
+//!
+//! Every message is handled inside a `handle` span whose parent is the span that was
+//! current when it was sent, so the work shows up under the request that caused it. The
+//! mailbox also emits `enqueued`, `dequeued` and `replied` events at trace level.
+
+use crate::mailbox::{self, MailboxConfig, SendError};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tracing::Span;
+
+/// The number of messages that can be queued for an actor by default.
+pub const MAILBOX_CAPACITY: usize = 32;
+
+/// Behavior for a type that can be run as an actor.
+///
+/// The state is kept separate from the actor itself, so the same behavior can be
+/// started with any initial state.
+pub trait Actor: Send + 'static {
+    /// The data owned by the running actor.
+    type State: Send + 'static;
+    /// The messages the actor understands.
+    type Message: Send + 'static;
+    /// The value sent back to callers that ask for a reply.
+    type Reply: Send + 'static;
+
+    /// Handles a single message, updating the state and producing a reply.
+    ///
+    /// Messages are handled sequentially, in the order in which they were received.
+    fn handle(&mut self, state: &mut Self::State, message: Self::Message) -> Self::Reply;
+
+    /// Handles several messages that were waiting in the mailbox together, producing one
+    /// reply per message, in order.
+    ///
+    /// This is only called when the mailbox's `max_batch` is more than 1. Override it to
+    /// apply a batch more cheaply than one message at a time, or to give every read in the
+    /// batch the same answer. By default each message is passed to [`Actor::handle`].
+    fn handle_batch(
+        &mut self,
+        state: &mut Self::State,
+        messages: Vec<Self::Message>,
+    ) -> Vec<Self::Reply> {
+        messages
+            .into_iter()
+            .map(|message| self.handle(state, message))
+            .collect()
+    }
+
+    /// A short name for the kind of message, used to group [`ActorMetrics::commands`].
+    ///
+    /// By default every message is counted as `"message"`.
+    fn message_name(_message: &Self::Message) -> &'static str {
+        "message"
+    }
+
+    /// Called once the actor has handled its last message, before its task exits.
+    ///
+    /// Override this to flush anything the actor buffers, such as persisted state.
+    fn stopped(&mut self, _state: &mut Self::State) {}
+}
+
+/// Errors that can occur when communicating with an actor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorError {
+    /// The actor is no longer running, so the message could not be delivered.
+    Closed,
+    /// The actor received the message but dropped the reply channel without answering.
+    ReplyDropped,
+    /// The actor did not reply in time.
+    Timeout,
+    /// The actor's mailbox is full and its overflow policy rejects new messages.
+    Full,
+}
+
+impl std::fmt::Display for ActorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActorError::Closed => write!(f, "the actor is not running"),
+            ActorError::ReplyDropped => write!(f, "the actor dropped the reply"),
+            ActorError::Timeout => write!(f, "the actor did not reply in time"),
+            ActorError::Full => write!(f, "the actor's mailbox is full"),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {}
+
+impl<T> From<SendError<T>> for ActorError {
+    fn from(err: SendError<T>) -> Self {
+        match err {
+            SendError::Closed(_) => ActorError::Closed,
+            SendError::Full(_) => ActorError::Full,
+        }
+    }
+}
+
+/// Which of the actor's two mailbox lanes a message travels in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// The normal lane, for bulk work. Messages are handled in the order they were sent.
+    #[default]
+    Normal,
+    /// The high-priority lane, for reads, health checks and control work. These overtake
+    /// every queued normal message, including ones the same caller sent earlier.
+    High,
+}
+
+/// How one kind of message has fared, as named by [`Actor::message_name`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandMetrics {
+    /// How many of these messages have been handled.
+    pub count: u64,
+    /// The total time from being queued to being handled, across all of them.
+    pub total_latency: Duration,
+    /// The longest time any one of them took from being queued to being handled.
+    pub max_latency: Duration,
+}
+
+impl CommandMetrics {
+    /// The average time from being queued to being handled, or zero if none were.
+    pub fn mean_latency(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        self.total_latency.div_f64(self.count as f64)
+    }
+}
+
+/// Where an actor is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActorStatus {
+    /// The actor accepts and handles messages.
+    Running,
+    /// The actor refuses new messages, but is still handling the ones already queued.
+    Stopping,
+    /// The actor's task has exited.
+    Stopped,
+}
+
+impl std::fmt::Display for ActorStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActorStatus::Running => write!(f, "running"),
+            ActorStatus::Stopping => write!(f, "stopping"),
+            ActorStatus::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// A snapshot of an actor's mailbox and how much work it has done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActorMetrics {
+    /// The number of messages waiting to be handled, in both lanes.
+    pub queue_depth: usize,
+    /// The number of messages waiting in the high-priority lane.
+    pub priority_queue_depth: usize,
+    /// The capacity and overflow policy of each lane of the mailbox.
+    pub mailbox: MailboxConfig,
+    /// The number of messages handled so far.
+    pub processed: u64,
+    /// Messages refused because their lane was full.
+    pub rejected: u64,
+    /// Queued messages discarded to make room for newer ones.
+    pub dropped: u64,
+    /// Messages dropped unhandled because their caller's deadline had passed.
+    pub expired: u64,
+    /// Counts and latencies for each kind of message, by name.
+    pub commands: BTreeMap<&'static str, CommandMetrics>,
+}
+
+/// A snapshot of an actor taken by [`ActorRef::inspect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Introspection<S> {
+    /// The actor's state when the snapshot was taken.
+    pub state: S,
+    /// How long the actor has been running since it was started or last restarted.
+    pub uptime: Duration,
+    /// The actor's metrics, including its mailbox depth and processed count.
+    pub metrics: ActorMetrics,
+}
+
+/// Counters updated by the actor loop and read by [`ActorRef::metrics`].
+#[derive(Default)]
+struct Stats {
+    processed: AtomicU64,
+    expired: AtomicU64,
+    commands: Mutex<HashMap<&'static str, CommandMetrics>>,
+    /// When the actor last started, or restarted.
+    started_at: Mutex<Option<Instant>>,
+}
+
+impl Stats {
+    fn record(&self, name: &'static str, latency: Duration) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        let mut commands = self.commands.lock().unwrap();
+        let command = commands.entry(name).or_default();
+        command.count += 1;
+        command.total_latency += latency;
+        command.max_latency = command.max_latency.max(latency);
+    }
+}
+
+/// Called with the actor's state after every change. Returns `false` once nobody is
+/// listening any more, to be removed.
+pub(crate) type Observer<A> = Box<dyn FnMut(&<A as Actor>::State) -> bool + Send>;
+
+/// Work for the actor's own loop, run between messages with full access to the actor.
+pub(crate) type Control<A> =
+    Box<dyn FnOnce(&mut A, &mut <A as Actor>::State, &mut Vec<Observer<A>>) + Send>;
+
+/// Called with every message the actor is about to handle, before it is handled.
+pub(crate) type Tap<A> = Box<dyn FnMut(&<A as Actor>::Message) + Send>;
+
+/// Something on its way to an actor.
+pub(crate) enum Envelope<A: Actor> {
+    /// A message, along with somewhere to send the reply.
+    Message {
+        message: A::Message,
+        reply: Option<oneshot::Sender<A::Reply>>,
+        /// If the actor dequeues the message after this point, nobody is waiting for the
+        /// reply any more and the message is dropped unhandled.
+        deadline: Option<Instant>,
+        /// When the message was sent, for measuring latency.
+        queued_at: Instant,
+        /// The span the message was sent from, which its handling is traced under.
+        span: Span,
+    },
+    /// Work handled by the actor loop rather than by [`Actor::handle`].
+    Control(Control<A>),
+    /// A tap to call with every message after this one.
+    #[cfg(any(test, feature = "test-util"))]
+    Tap(Tap<A>),
+}
+
+impl<A: Actor> Envelope<A> {
+    /// Wraps a message sent from the current span.
+    fn message(
+        message: A::Message,
+        reply: Option<oneshot::Sender<A::Reply>>,
+        deadline: Option<Instant>,
+    ) -> Self {
+        Envelope::Message {
+            message,
+            reply,
+            deadline,
+            queued_at: Instant::now(),
+            span: Span::current(),
+        }
+    }
+}
+
+impl<A: Actor> mailbox::Discard for Envelope<A> {
+    /// Only messages are discarded. Control work and taps come from the actor's own
+    /// methods, whose callers would be left with no reply and no reason.
+    fn may_discard(&self) -> bool {
+        matches!(self, Envelope::Message { .. })
+    }
+}
+
+/// A cloneable handle to a running actor.
+///
+/// The actor keeps running for as long as at least one `ActorRef` exists.
+///
+/// Calls wait as long as it takes unless the handle has a timeout or a deadline; see
+/// [`ActorRef::with_timeout`] and [`ActorRef::with_deadline`].
+pub struct ActorRef<A: Actor> {
+    sender: mailbox::Sender<Envelope<A>>,
+    priority_sender: mailbox::Sender<Envelope<A>>,
+    /// The lane this handle's messages travel in.
+    priority: Priority,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Set to `true` to ask the actor to stop.
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Becomes `true`, or is closed, once the actor's task has exited.
+    stopped: watch::Receiver<bool>,
+    stats: Arc<Stats>,
+}
+
+impl<A: Actor> Clone for ActorRef<A> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            priority_sender: self.priority_sender.clone(),
+            priority: self.priority,
+            timeout: self.timeout,
+            deadline: self.deadline,
+            shutdown: self.shutdown.clone(),
+            stopped: self.stopped.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<A: Actor> std::fmt::Debug for ActorRef<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActorRef")
+            .field("actor", &std::any::type_name::<A>())
+            .field("closed", &self.sender.is_closed())
+            .field("priority", &self.priority)
+            .field("timeout", &self.timeout)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl<A: Actor> ActorRef<A> {
+    /// Returns a copy of this handle that gives up on every call after `timeout`.
+    ///
+    /// The timeout is measured from the start of each call, and applies to both
+    /// queueing the message and waiting for the reply.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this handle whose calls all give up at `deadline`.
+    ///
+    /// This is useful for propagating a deadline from an incoming request. If the
+    /// handle also has a timeout, whichever expires first wins.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this handle that sends its messages in the given lane.
+    ///
+    /// High-priority messages are handled before any queued normal ones, so a read made
+    /// this way may not see writes that are still waiting in the normal lane.
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    /// The mailbox lane for this handle's messages.
+    fn lane(&self) -> &mailbox::Sender<Envelope<A>> {
+        match self.priority {
+            Priority::Normal => &self.sender,
+            Priority::High => &self.priority_sender,
+        }
+    }
+
+    /// The point at which a call starting now should give up, if any.
+    fn call_deadline(&self) -> Option<Instant> {
+        let from_timeout = self.timeout.map(|timeout| Instant::now() + timeout);
+        match (from_timeout, self.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Sends a message to the actor without waiting for it to be handled.
+    ///
+    /// The reply is discarded. If the handle has a timeout or deadline, it only limits
+    /// how long we wait for room in the mailbox.
+    ///
+    /// # Errors
+    /// Returns [`ActorError::Closed`] if the actor is no longer running,
+    /// [`ActorError::Full`] if its mailbox is full and rejects new messages, or
+    /// [`ActorError::Timeout`] if the mailbox stayed full until the deadline.
+    pub async fn tell(&self, message: A::Message) -> Result<(), ActorError> {
+        let name = A::message_name(&message);
+        self.send(self.lane(), Envelope::message(message, None, None))
+            .await?;
+        self.enqueued(name);
+        Ok(())
+    }
+
+    /// Emits the event for a message having been put in the mailbox.
+    fn enqueued(&self, name: &'static str) {
+        tracing::trace!(
+            actor = std::any::type_name::<A>(),
+            command = name,
+            priority = ?self.priority,
+            queue_depth = self.lane().len(),
+            "enqueued"
+        );
+    }
+
+    /// Puts an envelope in a lane of the mailbox, waiting for room until the call deadline.
+    async fn send(
+        &self,
+        lane: &mailbox::Sender<Envelope<A>>,
+        envelope: Envelope<A>,
+    ) -> Result<(), ActorError> {
+        let send = lane.send(envelope);
+        let result = match self.call_deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline, send)
+                .await
+                .map_err(|_| ActorError::Timeout)?,
+            None => send.await,
+        };
+        Ok(result?)
+    }
+
+    /// Runs `work` inside the actor loop and waits for its result.
+    pub(crate) async fn control<R: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut A, &mut A::State, &mut Vec<Observer<A>>) -> R + Send + 'static,
+    ) -> Result<R, ActorError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let envelope = Envelope::Control(Box::new(move |actor, state, observers| {
+            let _ = result_tx.send(work(actor, state, observers));
+        }));
+        self.send(self.lane(), envelope).await?;
+        let result = async { result_rx.await.map_err(|_| ActorError::ReplyDropped) };
+        match self.call_deadline() {
+            Some(deadline) => tokio::time::timeout_at(deadline, result)
+                .await
+                .map_err(|_| ActorError::Timeout)?,
+            None => result.await,
+        }
+    }
+
+    /// Runs `work` inside the actor, with nothing else handled until it finishes, and
+    /// returns its result.
+    ///
+    /// Use this to combine several steps into one that no other caller can interleave
+    /// with. `work` gets the actor and its state: changes made through
+    /// [`Actor::handle`] behave exactly like messages, while changes made directly to the
+    /// state skip anything `handle` does, such as journaling. It runs on the actor's task,
+    /// so it must not block.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply, for
+    /// example because `work` panicked.
+    pub async fn transaction<R: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut A, &mut A::State) -> R + Send + 'static,
+    ) -> Result<R, ActorError> {
+        self.control(move |actor, state, _observers| work(actor, state))
+            .await
+    }
+
+    /// Subscribes to changes in the actor's state.
+    ///
+    /// The returned receiver starts with the current state, and is updated whenever a
+    /// message changes it. Messages that leave the state as it was don't notify.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub async fn subscribe(&self) -> Result<watch::Receiver<A::State>, ActorError>
+    where
+        A::State: Clone + PartialEq + Sync,
+    {
+        self.control(|_actor, state: &mut A::State, observers| {
+            let (changes_tx, changes_rx) = watch::channel(state.clone());
+            observers.push(Box::new(move |state: &A::State| {
+                changes_tx.send_if_modified(|seen| {
+                    if seen == state {
+                        return false;
+                    }
+                    seen.clone_from(state);
+                    true
+                });
+                !changes_tx.is_closed()
+            }));
+            changes_rx
+        })
+        .await
+    }
+
+    /// Sends a message to the actor and waits for its reply.
+    ///
+    /// # Errors
+    /// Returns [`ActorError::Closed`] if the actor is no longer running,
+    /// [`ActorError::Full`] if its mailbox is full and rejects new messages,
+    /// [`ActorError::ReplyDropped`] if it stopped before replying or the message was
+    /// discarded from a full mailbox, or [`ActorError::Timeout`] if the handle's timeout
+    /// or deadline passed first.
+    pub async fn ask(&self, message: A::Message) -> Result<A::Reply, ActorError> {
+        let deadline = self.call_deadline();
+        let call = self.call(message, deadline);
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, call)
+                .await
+                .map_err(|_| ActorError::Timeout)?,
+            None => call.await,
+        }
+    }
+
+    /// Queues a message and waits for the reply, with no limit of its own on how long
+    /// that takes. `deadline` only tells the actor when to stop bothering.
+    async fn call(
+        &self,
+        message: A::Message,
+        deadline: Option<Instant>,
+    ) -> Result<A::Reply, ActorError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let name = A::message_name(&message);
+        let envelope = Envelope::message(message, Some(reply_tx), deadline);
+        self.lane().send(envelope).await?;
+        self.enqueued(name);
+        reply_rx.await.map_err(|_| unanswered(deadline))
+    }
+
+    /// Like [`ActorRef::tell`], but blocks the current thread instead of being async.
+    ///
+    /// This is for code that isn't async: plain threads, thread pools such as rayon, and
+    /// [`tokio::task::spawn_blocking`]. It waits for room in a full mailbox for as long
+    /// as it takes, whatever the handle's timeout.
+    ///
+    /// # Errors
+    /// The same as [`ActorRef::tell`], apart from [`ActorError::Timeout`].
+    ///
+    /// # Panics
+    /// Panics if called from async code while the mailbox waits for room, where blocking
+    /// would stall the runtime.
+    pub fn blocking_tell(&self, message: A::Message) -> Result<(), ActorError> {
+        let name = A::message_name(&message);
+        let envelope = Envelope::message(message, None, None);
+        self.lane().blocking_send(envelope)?;
+        self.enqueued(name);
+        Ok(())
+    }
+
+    /// Like [`ActorRef::ask`], but blocks the current thread instead of being async.
+    ///
+    /// This is for code that isn't async: plain threads, thread pools such as rayon, and
+    /// [`tokio::task::spawn_blocking`]. The handle's timeout and deadline are passed on
+    /// to the actor, which drops the message unhandled if it's still queued when they
+    /// pass. Unlike [`ActorRef::ask`], the call doesn't give up by itself, so it can run
+    /// past them while waiting for room or for the message the actor is on.
+    ///
+    /// # Errors
+    /// The same as [`ActorRef::ask`].
+    ///
+    /// # Panics
+    /// Panics if called from async code, where blocking would stall the runtime.
+    pub fn blocking_ask(&self, message: A::Message) -> Result<A::Reply, ActorError> {
+        let deadline = self.call_deadline();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let name = A::message_name(&message);
+        let envelope = Envelope::message(message, Some(reply_tx), deadline);
+        self.lane().blocking_send(envelope)?;
+        self.enqueued(name);
+        reply_rx.blocking_recv().map_err(|_| unanswered(deadline))
+    }
+
+    /// Checks that the actor is running and responsive, without involving its behavior.
+    ///
+    /// The check travels in the high-priority lane, so it isn't held up by a backlog of
+    /// normal messages; a slow reply means the actor is stuck on its current message.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply in time.
+    pub async fn ping(&self) -> Result<(), ActorError> {
+        self.with_priority(Priority::High)
+            .control(|_actor, _state, _observers| ())
+            .await
+    }
+
+    /// Returns `true` if the actor can no longer receive messages, because it has
+    /// stopped or is shutting down.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Where the actor is in its lifecycle.
+    pub fn status(&self) -> ActorStatus {
+        // A dropped sender means the task went away without saying so, e.g. it panicked.
+        if *self.stopped.borrow() || self.stopped.has_changed().is_err() {
+            ActorStatus::Stopped
+        } else if self.sender.is_closed() {
+            ActorStatus::Stopping
+        } else {
+            ActorStatus::Running
+        }
+    }
+
+    /// Reads the actor's current metrics.
+    ///
+    /// This doesn't go through the mailbox, so it works even while the actor is busy.
+    pub fn metrics(&self) -> ActorMetrics {
+        ActorMetrics {
+            queue_depth: self.sender.len() + self.priority_sender.len(),
+            priority_queue_depth: self.priority_sender.len(),
+            mailbox: self.sender.config(),
+            processed: self.stats.processed.load(Ordering::Relaxed),
+            rejected: self.sender.rejected() + self.priority_sender.rejected(),
+            dropped: self.sender.dropped() + self.priority_sender.dropped(),
+            expired: self.stats.expired.load(Ordering::Relaxed),
+            commands: self
+                .stats
+                .commands
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, command)| (*name, *command))
+                .collect(),
+        }
+    }
+
+    /// Takes a snapshot of the actor: its current state, how long it has been running
+    /// and its metrics.
+    ///
+    /// The snapshot is taken by the actor between messages, in this handle's lane, so it
+    /// reflects every message this handle sent before.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub async fn inspect(&self) -> Result<Introspection<A::State>, ActorError>
+    where
+        A::State: Clone,
+    {
+        let this = self.clone();
+        self.control(move |_actor, state: &mut A::State, _observers| {
+            let started_at = *this.stats.started_at.lock().unwrap();
+            Introspection {
+                state: state.clone(),
+                uptime: started_at.map_or(Duration::ZERO, |started_at| started_at.elapsed()),
+                metrics: this.metrics(),
+            }
+        })
+        .await
+    }
+
+    /// Stops the actor gracefully and waits for it to finish.
+    ///
+    /// The actor stops accepting new messages straight away, so further calls from any
+    /// handle fail with [`ActorError::Closed`]. Messages that were already queued are
+    /// still handled, then the actor's [`Actor::stopped`] hook runs and its task exits.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.stopped().await;
+    }
+
+    /// Waits until the actor's task has exited, without asking it to stop.
+    pub async fn stopped(&self) {
+        wait_stopped(self.stopped.clone()).await;
+    }
+
+    /// Like [`ActorRef::stopped`], but without keeping the actor alive while waiting.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn when_stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        wait_stopped(self.stopped.clone())
+    }
+
+    /// Calls `tap` with every message the actor handles after the tap reaches it, just
+    /// before handling it.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) async fn tap(&self, tap: Tap<A>) -> Result<(), ActorError> {
+        self.send(self.lane(), Envelope::Tap(tap)).await
+    }
+}
+
+/// Resolves once an actor's task has exited.
+async fn wait_stopped(mut stopped: watch::Receiver<bool>) {
+    // An error means the task went away without saying so, e.g. it panicked.
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+/// The receiving end of an actor's mailbox.
+pub(crate) struct Mailbox<A: Actor> {
+    receiver: mailbox::Receiver<Envelope<A>>,
+    priority_receiver: mailbox::Receiver<Envelope<A>>,
+    shutdown: watch::Receiver<bool>,
+    stopped: watch::Sender<bool>,
+    /// Kept with the mailbox, so subscriptions survive the actor being restarted.
+    observers: Vec<Observer<A>>,
+    /// Kept with the mailbox, like observers.
+    taps: Vec<Tap<A>>,
+    /// Kept with the mailbox, so metrics add up across restarts.
+    stats: Arc<Stats>,
+    max_batch: usize,
+}
+
+/// Creates a mailbox for an actor, along with a handle that sends to it.
+///
+/// Each lane gets the configured capacity and overflow policy.
+///
+/// # Panics
+/// Panics if the configured capacity or batch size is 0.
+pub(crate) fn mailbox<A: Actor>(config: MailboxConfig) -> (ActorRef<A>, Mailbox<A>) {
+    let (sender, receiver) = mailbox::channel(config);
+    let (priority_sender, priority_receiver) = mailbox::channel(config);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (stopped_tx, stopped_rx) = watch::channel(false);
+    let stats = Arc::new(Stats::default());
+    let actor_ref = ActorRef {
+        sender,
+        priority_sender,
+        priority: Priority::Normal,
+        timeout: None,
+        deadline: None,
+        shutdown: Arc::new(shutdown_tx),
+        stopped: stopped_rx,
+        stats: stats.clone(),
+    };
+    let mailbox = Mailbox {
+        receiver,
+        priority_receiver,
+        shutdown: shutdown_rx,
+        stopped: stopped_tx,
+        observers: Vec::new(),
+        taps: Vec::new(),
+        stats,
+        max_batch: config.max_batch,
+    };
+    (actor_ref, mailbox)
+}
+
+/// Why a message's reply never came: the actor drops messages that expired in its queue
+/// without replying.
+fn unanswered(deadline: Option<Instant>) -> ActorError {
+    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+        ActorError::Timeout
+    } else {
+        ActorError::ReplyDropped
+    }
+}
+
+/// Someone waiting for a message in the current batch to be handled.
+struct Caller<R> {
+    reply: Option<oneshot::Sender<R>>,
+    name: &'static str,
+    queued_at: Instant,
+    span: Span,
+}
+
+/// Runs an actor's message loop until every handle to it has been dropped, or it is
+/// asked to shut down and has emptied its mailbox.
+///
+/// `after_message` is called with the state after each message, or batch of messages,
+/// has been handled.
+pub(crate) async fn run<A: Actor>(
+    mut actor: A,
+    mut state: A::State,
+    mailbox: &mut Mailbox<A>,
+    mut after_message: impl FnMut(&A::State),
+) {
+    let max_batch = mailbox.max_batch;
+    let mut envelopes = Vec::with_capacity(max_batch);
+    let mut priority_envelopes = Vec::with_capacity(max_batch);
+    let mut messages = Vec::with_capacity(max_batch);
+    let mut callers = Vec::with_capacity(max_batch);
+    let mut closing = false;
+    *mailbox.stats.started_at.lock().unwrap() = Some(Instant::now());
+    loop {
+        tokio::select! {
+            // Checked first, so a flood of messages can't hold up a shutdown.
+            biased;
+            _ = mailbox.shutdown.wait_for(|shutdown| *shutdown), if !closing => {
+                // Refuse new messages, but keep going until both lanes are empty.
+                mailbox.receiver.close();
+                mailbox.priority_receiver.close();
+                closing = true;
+                continue;
+            }
+            // The high-priority lane is always emptied before the normal one.
+            1.. = mailbox.priority_receiver.recv_many(&mut priority_envelopes, max_batch) => {}
+            1.. = mailbox.receiver.recv_many(&mut envelopes, max_batch) => {}
+            else => break,
+        }
+        // Only the lane that won the select has received anything.
+        let batch = if priority_envelopes.is_empty() {
+            &mut envelopes
+        } else {
+            &mut priority_envelopes
+        };
+
+        for envelope in batch.drain(..) {
+            match envelope {
+                Envelope::Message {
+                    message,
+                    reply,
+                    deadline,
+                    queued_at,
+                    span,
+                } => {
+                    let name = A::message_name(&message);
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        // The caller has already given up, so don't spend time on it.
+                        mailbox.stats.expired.fetch_add(1, Ordering::Relaxed);
+                        tracing::trace!(parent: &span, command = name, "expired");
+                        continue;
+                    }
+                    tracing::trace!(
+                        parent: &span,
+                        command = name,
+                        waited_us = queued_at.elapsed().as_micros() as u64,
+                        "dequeued"
+                    );
+                    for tap in &mut mailbox.taps {
+                        tap(&message);
+                    }
+                    callers.push(Caller {
+                        reply,
+                        name,
+                        queued_at,
+                        span,
+                    });
+                    messages.push(message);
+                }
+                Envelope::Control(work) => {
+                    // Messages queued before the control work are handled before it.
+                    handle_messages(
+                        &mut actor,
+                        &mut state,
+                        mailbox,
+                        &mut messages,
+                        &mut callers,
+                        &mut after_message,
+                    );
+                    work(&mut actor, &mut state, &mut mailbox.observers);
+                    after_message(&state);
+                    mailbox.observers.retain_mut(|observer| observer(&state));
+                }
+                #[cfg(any(test, feature = "test-util"))]
+                Envelope::Tap(tap) => mailbox.taps.push(tap),
+            }
+        }
+        handle_messages(
+            &mut actor,
+            &mut state,
+            mailbox,
+            &mut messages,
+            &mut callers,
+            &mut after_message,
+        );
+    }
+
+    actor.stopped(&mut state);
+    mailbox.stopped.send_replace(true);
+}
+
+/// Handles the messages collected from the current batch, then answers their callers.
+fn handle_messages<A: Actor>(
+    actor: &mut A,
+    state: &mut A::State,
+    mailbox: &mut Mailbox<A>,
+    messages: &mut Vec<A::Message>,
+    callers: &mut Vec<Caller<A::Reply>>,
+    after_message: &mut impl FnMut(&A::State),
+) {
+    let actor_name = std::any::type_name::<A>();
+    let replies = match messages.len() {
+        0 => return,
+        1 => {
+            let caller = &callers[0];
+            let span = tracing::debug_span!(
+                parent: &caller.span,
+                "handle",
+                actor = actor_name,
+                command = caller.name
+            );
+            span.in_scope(|| vec![actor.handle(state, messages.pop().unwrap())])
+        }
+        _ => {
+            // A batch is handled in one go, so it can only have one parent. It is linked
+            // to each of its callers' spans instead.
+            let span = tracing::debug_span!(
+                parent: None,
+                "handle_batch",
+                actor = actor_name,
+                size = messages.len()
+            );
+            for caller in callers.iter() {
+                span.follows_from(&caller.span);
+            }
+            span.in_scope(|| actor.handle_batch(state, std::mem::take(messages)))
+        }
+    };
+    assert_eq!(
+        replies.len(),
+        callers.len(),
+        "handle_batch must reply to every message"
+    );
+    for caller in callers.iter() {
+        mailbox
+            .stats
+            .record(caller.name, caller.queued_at.elapsed());
+    }
+    after_message(state);
+    mailbox.observers.retain_mut(|observer| observer(state));
+    for (caller, reply) in callers.drain(..).zip(replies) {
+        let Caller {
+            reply: reply_tx,
+            name,
+            queued_at,
+            span,
+        } = caller;
+        if let Some(reply_tx) = reply_tx {
+            let delivered = reply_tx.send(reply).is_ok();
+            tracing::trace!(
+                parent: &span,
+                command = name,
+                latency_us = queued_at.elapsed().as_micros() as u64,
+                delivered,
+                "replied"
+            );
+        }
+    }
+}
+
+/// Spawns an actor onto the Tokio runtime and returns a handle to it.
+///
+/// # Arguments
+/// * `actor` - The behavior to run
+/// * `state` - The initial state owned by the actor
+///
+/// # Returns
+/// An `ActorRef` that can be cloned and shared between tasks.
+pub fn spawn<A: Actor>(actor: A, state: A::State) -> ActorRef<A> {
+    spawn_with(actor, state, MailboxConfig::default())
+}
+
+/// Spawns an actor with a mailbox of the given size, overflow policy and batch size.
+///
+/// # Arguments
+/// * `actor` - The behavior to run
+/// * `state` - The initial state owned by the actor
+/// * `config` - The mailbox's capacity, what to do when it is full, and how many
+///   messages to handle at once
+///
+/// # Returns
+/// An `ActorRef` that can be cloned and shared between tasks.
+///
+/// # Panics
+/// Panics if the configured capacity or batch size is 0.
+pub fn spawn_with<A: Actor>(actor: A, state: A::State, config: MailboxConfig) -> ActorRef<A> {
+    let (actor_ref, mut mailbox) = mailbox(config);
+    tokio::spawn(async move {
+        run(actor, state, &mut mailbox, |_| {}).await;
+    });
+    actor_ref
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    struct Greeter;
+
+    impl Actor for Greeter {
+        type State = Vec<String>;
+        type Message = String;
+        type Reply = usize;
+
+        fn handle(&mut self, state: &mut Vec<String>, message: String) -> usize {
+            state.push(message);
+            state.len()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ask_returns_reply() {
+        let actor = spawn(Greeter, Vec::new());
+        assert_eq!(actor.ask("hello".to_string()).await, Ok(1));
+        assert_eq!(actor.ask("world".to_string()).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_tell_is_processed_in_order() {
+        let actor = spawn(Greeter, vec!["first".to_string()]);
+        actor.tell("second".to_string()).await.unwrap();
+        assert_eq!(actor.ask("third".to_string()).await, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn test_cloned_refs_share_one_actor() {
+        let actor = spawn(Greeter, Vec::new());
+        let clone = actor.clone();
+        actor.tell("a".to_string()).await.unwrap();
+        clone.tell("b".to_string()).await.unwrap();
+        assert_eq!(actor.ask("c".to_string()).await, Ok(3));
+    }
+
+    struct Panicky;
+
+    impl Actor for Panicky {
+        type State = ();
+        type Message = ();
+        type Reply = ();
+
+        fn handle(&mut self, _state: &mut (), _message: ()) {
+            panic!("this actor always fails");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ask_reports_dropped_reply() {
+        let actor = spawn(Panicky, ());
+        assert_eq!(actor.ask(()).await, Err(ActorError::ReplyDropped));
+    }
+
+    #[tokio::test]
+    async fn test_messages_to_a_dead_actor_report_closed() {
+        let actor = spawn(Panicky, ());
+        let _ = actor.ask(()).await;
+        while !actor.is_closed() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(actor.tell(()).await, Err(ActorError::Closed));
+        assert_eq!(actor.ask(()).await, Err(ActorError::Closed));
+    }
+
+    /// Counts the messages it handles, but sleeps before every reply.
+    struct Sleepy;
+
+    impl Actor for Sleepy {
+        type State = u32;
+        type Message = Duration;
+        type Reply = u32;
+
+        fn handle(&mut self, handled: &mut u32, nap: Duration) -> u32 {
+            std::thread::sleep(nap);
+            *handled += 1;
+            *handled
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_ask_times_out() {
+        let actor = spawn(Sleepy, 0).with_timeout(Duration::from_millis(20));
+        let result = actor.ask(Duration::from_millis(200)).await;
+        assert_eq!(result, Err(ActorError::Timeout));
+    }
+
+    #[tokio::test]
+    async fn test_ask_with_passed_deadline_times_out() {
+        let actor = spawn(Sleepy, 0).with_deadline(Instant::now());
+        assert_eq!(actor.ask(Duration::ZERO).await, Err(ActorError::Timeout));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_expired_messages_are_dropped_unhandled() {
+        let actor = spawn(Sleepy, 0);
+
+        // Keep the actor busy, so the next message waits in the queue past its deadline.
+        actor.tell(Duration::from_millis(100)).await.unwrap();
+
+        let impatient = actor.with_timeout(Duration::from_millis(10));
+        assert_eq!(
+            impatient.ask(Duration::ZERO).await,
+            Err(ActorError::Timeout)
+        );
+
+        // Only the slow message and this one were handled.
+        assert_eq!(actor.ask(Duration::ZERO).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queued_messages() {
+        let actor = spawn(Greeter, Vec::new());
+        for word in ["a", "b", "c"] {
+            actor.tell(word.to_string()).await.unwrap();
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        actor
+            .sender
+            .send(Envelope::message("d".to_string(), Some(reply_tx), None))
+            .await
+            .unwrap();
+
+        actor.shutdown().await;
+        assert_eq!(reply_rx.await, Ok(4));
+        assert!(actor.is_closed());
+        assert_eq!(actor.tell("e".to_string()).await, Err(ActorError::Closed));
+    }
+
+    struct Flusher(Arc<std::sync::Mutex<Option<u32>>>);
+
+    impl Actor for Flusher {
+        type State = u32;
+        type Message = u32;
+        type Reply = ();
+
+        fn handle(&mut self, total: &mut u32, amount: u32) {
+            *total += amount;
+        }
+
+        fn stopped(&mut self, total: &mut u32) {
+            *self.0.lock().unwrap() = Some(*total);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stopped_hook_runs_before_shutdown_returns() {
+        let flushed = Arc::new(std::sync::Mutex::new(None));
+        let actor = spawn(Flusher(flushed.clone()), 0);
+        actor.tell(2).await.unwrap();
+        actor.tell(3).await.unwrap();
+
+        let other = actor.clone();
+        other.shutdown().await;
+        assert_eq!(*flushed.lock().unwrap(), Some(5));
+        // Every handle sees that the actor has stopped.
+        actor.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_stopped_resolves_when_handles_are_dropped() {
+        let actor = spawn(Greeter, Vec::new());
+        let watcher = actor.stopped.clone();
+        drop(actor);
+
+        let mut watcher = watcher;
+        let _ = watcher.wait_for(|stopped| *stopped).await;
+        assert!(*watcher.borrow());
+    }
+
+    /// A span's name, and its parent's name if it has one.
+    type SpanNames = (&'static str, Option<&'static str>);
+
+    /// Records the name of every span created, along with its parent's name.
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<SpanNames>>>,
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            _attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|parent| parent.name());
+            self.spans.lock().unwrap().push((span.name(), parent));
+        }
+    }
+
+    fn record_spans() -> (SpanRecorder, tracing::subscriber::DefaultGuard) {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        (recorder, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_handled_in_a_span_under_the_caller() {
+        let (recorder, _guard) = record_spans();
+        let actor = spawn(Greeter, Vec::new());
+
+        let request = tracing::info_span!("request");
+        actor
+            .ask("hello".to_string())
+            .instrument(request)
+            .await
+            .unwrap();
+        actor.ask("untraced".to_string()).await.unwrap();
+
+        let spans = recorder.spans.lock().unwrap().clone();
+        assert_eq!(
+            spans,
+            [
+                ("request", None),
+                ("handle", Some("request")),
+                ("handle", None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_handled_in_one_span() {
+        let (recorder, _guard) = record_spans();
+        let (actor, mut mailbox) = mailbox::<Greeter>(MailboxConfig {
+            max_batch: 8,
+            ..MailboxConfig::default()
+        });
+        for word in ["a", "b", "c"] {
+            actor
+                .tell(word.to_string())
+                .instrument(tracing::info_span!("request"))
+                .await
+                .unwrap();
+        }
+        drop(actor);
+        run(Greeter, Vec::new(), &mut mailbox, |_| {}).await;
+
+        let spans = recorder.spans.lock().unwrap().clone();
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[3], ("handle_batch", None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inspect() {
+        let actor = spawn(Greeter, Vec::new());
+        actor.tell("a".to_string()).await.unwrap();
+        actor.tell("b".to_string()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let snapshot = actor.inspect().await.unwrap();
+        assert_eq!(snapshot.state, ["a", "b"]);
+        assert_eq!(snapshot.uptime, Duration::from_secs(5));
+        assert_eq!(snapshot.metrics.processed, 2);
+        assert_eq!(snapshot.metrics.queue_depth, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_resolves_after_panic() {
+        let actor = spawn(Panicky, ());
+        let _ = actor.ask(()).await;
+        actor.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_status_follows_the_lifecycle() {
+        let actor = spawn(Greeter, Vec::new());
+        assert_eq!(actor.status(), ActorStatus::Running);
+        actor.shutdown().await;
+        assert_eq!(actor.status(), ActorStatus::Stopped);
+
+        let panicky = spawn(Panicky, ());
+        let _ = panicky.ask(()).await;
+        panicky.stopped().await;
+        assert_eq!(panicky.status(), ActorStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sees_changes() {
+        let actor = spawn(Greeter, vec!["first".to_string()]);
+        let mut changes = actor.subscribe().await.unwrap();
+        assert_eq!(*changes.borrow_and_update(), vec!["first".to_string()]);
+
+        actor.tell("second".to_string()).await.unwrap();
+        changes.changed().await.unwrap();
+        assert_eq!(changes.borrow_and_update().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_are_dropped_when_they_stop_listening() {
+        let actor = spawn(Greeter, Vec::new());
+        let changes = actor.subscribe().await.unwrap();
+        drop(changes);
+
+        actor.tell("a".to_string()).await.unwrap();
+        let observers = actor.control(|_, _, observers| observers.len()).await;
+        assert_eq!(observers, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_ends_when_actor_stops() {
+        let actor = spawn(Greeter, Vec::new());
+        let mut changes = actor.subscribe().await.unwrap();
+        actor.shutdown().await;
+        assert!(changes.changed().await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_full_mailbox_rejects_messages() {
+        let config = MailboxConfig {
+            capacity: 1,
+            overflow: crate::mailbox::OverflowPolicy::Reject,
+            ..MailboxConfig::default()
+        };
+        let actor = spawn_with(Sleepy, 0, config);
+
+        // One message keeps the actor busy while the next fills the mailbox.
+        actor.tell(Duration::from_millis(100)).await.unwrap();
+        while actor.metrics().queue_depth > 0 {
+            tokio::task::yield_now().await;
+        }
+        actor.tell(Duration::ZERO).await.unwrap();
+        assert_eq!(actor.tell(Duration::ZERO).await, Err(ActorError::Full));
+        assert_eq!(actor.ask(Duration::ZERO).await, Err(ActorError::Full));
+
+        assert_eq!(actor.metrics().rejected, 2);
+
+        // Once the actor catches up, there's room again.
+        while actor.metrics().queue_depth > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(actor.ask(Duration::ZERO).await, Ok(3));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_full_mailbox_drops_oldest_messages() {
+        let config = MailboxConfig {
+            capacity: 1,
+            overflow: crate::mailbox::OverflowPolicy::DropOldest,
+            ..MailboxConfig::default()
+        };
+        let actor = spawn_with(Sleepy, 0, config);
+
+        actor.tell(Duration::from_millis(100)).await.unwrap();
+        while actor.metrics().queue_depth > 0 {
+            tokio::task::yield_now().await;
+        }
+        let (first, second) = tokio::join!(actor.ask(Duration::ZERO), async {
+            // Give the first ask a head start, so it's the oldest in the mailbox.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            actor.ask(Duration::ZERO).await
+        });
+        assert_eq!(first, Err(ActorError::ReplyDropped));
+        assert_eq!(second, Ok(2));
+        assert_eq!(actor.metrics().dropped, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_full_mailbox_never_drops_control_work() {
+        let config = MailboxConfig {
+            capacity: 1,
+            overflow: crate::mailbox::OverflowPolicy::DropOldest,
+            ..MailboxConfig::default()
+        };
+        let actor = spawn_with(Sleepy, 0, config);
+
+        actor.tell(Duration::from_millis(100)).await.unwrap();
+        while actor.metrics().queue_depth > 0 {
+            tokio::task::yield_now().await;
+        }
+        let (inspected, refused) = tokio::join!(actor.inspect(), async {
+            // Give the inspection a head start, so it's what fills the mailbox.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            actor.ask(Duration::ZERO).await
+        });
+        assert_eq!(inspected.unwrap().state, 1);
+        assert_eq!(refused, Err(ActorError::Full));
+        assert_eq!(actor.metrics().dropped, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_metrics_track_latency_and_expired_messages() {
+        let actor = spawn(Sleepy, 0);
+        actor.ask(Duration::from_millis(20)).await.unwrap();
+        let _ = actor
+            .with_deadline(Instant::now())
+            .ask(Duration::ZERO)
+            .await;
+        actor.ask(Duration::ZERO).await.unwrap();
+
+        let metrics = actor.metrics();
+        assert_eq!(metrics.processed, 2);
+        let messages = metrics.commands["message"];
+        assert_eq!(messages.count, 2);
+        assert!(messages.max_latency >= Duration::from_millis(20));
+        assert!(messages.mean_latency() <= messages.max_latency);
+        // The expired message may not have reached the actor, if the caller gave up first.
+        assert!(metrics.expired <= 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_high_priority_messages_overtake_the_backlog() {
+        let actor = spawn(Sleepy, 0);
+        actor.tell(Duration::from_millis(50)).await.unwrap();
+        while actor.metrics().queue_depth > 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..5 {
+            actor.tell(Duration::from_millis(20)).await.unwrap();
+        }
+
+        // Only the message already being handled goes first.
+        let urgent = actor.with_priority(Priority::High);
+        assert_eq!(urgent.ask(Duration::ZERO).await, Ok(2));
+        assert_eq!(actor.ask(Duration::ZERO).await, Ok(8));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_ping_is_not_held_up_by_the_backlog() {
+        let actor = spawn(Sleepy, 0);
+        for _ in 0..10 {
+            actor.tell(Duration::from_millis(20)).await.unwrap();
+        }
+        let started = Instant::now();
+        actor.ping().await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(actor.metrics().queue_depth > 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_both_lanes() {
+        let flushed = Arc::new(std::sync::Mutex::new(None));
+        let actor = spawn(Flusher(flushed.clone()), 0);
+        actor.tell(2).await.unwrap();
+        actor.with_priority(Priority::High).tell(3).await.unwrap();
+
+        actor.shutdown().await;
+        assert_eq!(*flushed.lock().unwrap(), Some(5));
+    }
+
+    /// Records the size of every batch it is given.
+    struct Batcher;
+
+    impl Actor for Batcher {
+        type State = Vec<usize>;
+        type Message = u32;
+        type Reply = u32;
+
+        fn handle(&mut self, batches: &mut Vec<usize>, message: u32) -> u32 {
+            batches.push(1);
+            message
+        }
+
+        fn handle_batch(&mut self, batches: &mut Vec<usize>, messages: Vec<u32>) -> Vec<u32> {
+            batches.push(messages.len());
+            messages
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_messages_are_handled_in_batches() {
+        let config = MailboxConfig {
+            max_batch: 4,
+            ..MailboxConfig::default()
+        };
+        let actor = spawn_with(Batcher, Vec::new(), config);
+        // Nothing is handled until this task yields, so all ten are waiting together.
+        for i in 0..10 {
+            actor.tell(i).await.unwrap();
+        }
+        assert_eq!(actor.ask(10).await, Ok(10));
+
+        let batches = actor.control(|_, batches, _| batches.clone()).await;
+        assert_eq!(batches, Ok(vec![4, 4, 3]));
+        assert_eq!(actor.metrics().processed, 11);
+    }
+
+    #[tokio::test]
+    async fn test_control_work_splits_a_batch() {
+        let config = MailboxConfig {
+            max_batch: 8,
+            ..MailboxConfig::default()
+        };
+        let actor = spawn_with(Batcher, Vec::new(), config);
+        actor.tell(1).await.unwrap();
+        actor.tell(2).await.unwrap();
+        let seen = actor.control(|_, batches, _| batches.clone());
+        let (seen, reply) = tokio::join!(seen, actor.ask(3));
+        // The control work ran after the first two messages, and before the third.
+        assert_eq!(seen, Ok(vec![2]));
+        assert_eq!(reply, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_is_not_interleaved() {
+        let actor = spawn(Greeter, Vec::new());
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..10 {
+            let actor = actor.clone();
+            tasks.spawn(async move {
+                actor
+                    .transaction(move |greeter, names| {
+                        let first = greeter.handle(names, format!("{i}a"));
+                        let second = greeter.handle(names, format!("{i}b"));
+                        (first, second)
+                    })
+                    .await
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            let (first, second) = result.unwrap().unwrap();
+            assert_eq!(second, first + 1);
+        }
+        assert_eq!(actor.ask("last".to_string()).await, Ok(21));
+    }
+
+    #[test]
+    fn test_blocking_calls_from_a_plain_thread() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let actor = runtime.block_on(async { spawn(Greeter, Vec::new()) });
+
+        actor.blocking_tell("a".to_string()).unwrap();
+        assert_eq!(actor.blocking_ask("b".to_string()), Ok(2));
+        let from_thread = std::thread::spawn({
+            let actor = actor.clone();
+            move || actor.blocking_ask("c".to_string())
+        });
+        assert_eq!(from_thread.join().unwrap(), Ok(3));
+    }
+
+    #[test]
+    fn test_blocking_ask_times_out() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let actor = runtime.block_on(async { spawn(Sleepy, 0) });
+        actor.blocking_tell(Duration::from_millis(100)).unwrap();
+        let impatient = actor.with_timeout(Duration::from_millis(20));
+        assert_eq!(
+            impatient.blocking_ask(Duration::ZERO),
+            Err(ActorError::Timeout)
+        );
+        assert_eq!(actor.blocking_ask(Duration::ZERO), Ok(2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_calls_work_in_spawn_blocking() {
+        let actor = spawn(Greeter, Vec::new());
+        let blocking = actor.clone();
+        let reply = tokio::task::spawn_blocking(move || blocking.blocking_ask("a".to_string()));
+        assert_eq!(reply.await.unwrap(), Ok(1));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot block the current thread from within a runtime")]
+    async fn test_blocking_calls_panic_in_async_code() {
+        let actor = spawn(Greeter, Vec::new());
+        let _ = actor.blocking_ask("a".to_string());
+    }
+}
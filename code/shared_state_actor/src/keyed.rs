@@ -0,0 +1,368 @@
+//! Many named counters, optionally sharded across several actors.
+//!
+//! Each key is owned by exactly one shard, picked by hashing the key. Commands for
+//! different shards are handled in parallel, so one busy key doesn't hold up the rest.
+//!
+//! Keys are usually picked by clients, so the number of counters, and the length of their
+//! names, are limited.
+
+use crate::actor::{self, Actor, ActorError, ActorRef};
+use crate::registry::{Registry, RegistryError};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// The longest key, in bytes, a counter can be created with.
+pub const MAX_KEY_LENGTH: usize = 128;
+/// How many counters [`start_keyed`] holds at most.
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// Commands that can be sent to a keyed counter actor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyedCommand {
+    /// Increments the named counter by 1, creating it if needed. Fails with
+    /// [`KeyedError::Overflow`] at `u64::MAX`.
+    Increment(String),
+    /// Retrieves the value of the named counter. Unknown counters are 0.
+    Get(String),
+    /// Retrieves every counter held by the actor.
+    List,
+    /// Removes the named counter.
+    Delete(String),
+}
+
+/// Replies from a keyed counter actor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyedReply {
+    /// The value of a counter, after an `Increment` or for a `Get`.
+    Value(u64),
+    /// The value a counter had when it was deleted, if it existed.
+    Removed(Option<u64>),
+    /// Every counter, for a `List`.
+    Entries(Vec<(String, u64)>),
+}
+
+/// Why a keyed counter command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyedError {
+    /// The command never reached the actor, or it didn't answer.
+    Actor(ActorError),
+    /// The counter is at `current`, which is `u64::MAX`, and was left there.
+    Overflow { current: u64 },
+    /// The key is empty or longer than [`MAX_KEY_LENGTH`].
+    InvalidKey,
+    /// The counter doesn't exist, and its shard already holds as many as it may.
+    TooManyKeys,
+}
+
+impl std::fmt::Display for KeyedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyedError::Actor(err) => err.fmt(f),
+            KeyedError::Overflow { current } => {
+                write!(f, "the counter is {current}, and can't go any higher")
+            }
+            KeyedError::InvalidKey => {
+                write!(f, "keys must be between 1 and {MAX_KEY_LENGTH} bytes long")
+            }
+            KeyedError::TooManyKeys => write!(f, "there are too many counters to add another"),
+        }
+    }
+}
+
+impl std::error::Error for KeyedError {}
+
+impl From<ActorError> for KeyedError {
+    fn from(err: ActorError) -> Self {
+        KeyedError::Actor(err)
+    }
+}
+
+/// An actor holding a map of named counters.
+#[derive(Debug)]
+pub struct KeyedCounter {
+    /// The most counters this actor holds.
+    max_keys: usize,
+}
+
+impl KeyedCounter {
+    /// Increments the counter called `key`, creating it if there's room.
+    fn increment(
+        &self,
+        counters: &mut HashMap<String, u64>,
+        key: String,
+    ) -> Result<u64, KeyedError> {
+        if let Some(counter) = counters.get_mut(&key) {
+            *counter = counter
+                .checked_add(1)
+                .ok_or(KeyedError::Overflow { current: *counter })?;
+            return Ok(*counter);
+        }
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Err(KeyedError::InvalidKey);
+        }
+        if counters.len() >= self.max_keys {
+            return Err(KeyedError::TooManyKeys);
+        }
+        counters.insert(key, 1);
+        Ok(1)
+    }
+}
+
+impl Actor for KeyedCounter {
+    type State = HashMap<String, u64>;
+    type Message = KeyedCommand;
+    /// The answer to the command, or why it was refused.
+    type Reply = Result<KeyedReply, KeyedError>;
+
+    fn handle(
+        &mut self,
+        counters: &mut HashMap<String, u64>,
+        command: KeyedCommand,
+    ) -> Self::Reply {
+        Ok(match command {
+            KeyedCommand::Increment(key) => KeyedReply::Value(self.increment(counters, key)?),
+            KeyedCommand::Get(key) => KeyedReply::Value(counters.get(&key).copied().unwrap_or(0)),
+            KeyedCommand::List => KeyedReply::Entries(
+                counters
+                    .iter()
+                    .map(|(key, value)| (key.clone(), *value))
+                    .collect(),
+            ),
+            KeyedCommand::Delete(key) => KeyedReply::Removed(counters.remove(&key)),
+        })
+    }
+}
+
+/// A cloneable handle to a set of keyed counter shards.
+#[derive(Clone, Debug)]
+pub struct KeyedHandle {
+    shards: Vec<ActorRef<KeyedCounter>>,
+}
+
+impl KeyedHandle {
+    /// The shard that owns `key`.
+    fn shard(&self, key: &str) -> &ActorRef<KeyedCounter> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let index = hasher.finish() % self.shards.len() as u64;
+        &self.shards[index as usize]
+    }
+
+    /// Increments the named counter by 1, creating it if it doesn't exist.
+    ///
+    /// # Returns
+    /// The counter's new value.
+    ///
+    /// # Errors
+    /// Returns a [`KeyedError`] if the actor is unavailable, the counter is at `u64::MAX`,
+    /// or it doesn't exist and can't be created.
+    pub async fn increment(&self, key: &str) -> Result<u64, KeyedError> {
+        match self
+            .shard(key)
+            .ask(KeyedCommand::Increment(key.to_string()))
+            .await??
+        {
+            KeyedReply::Value(value) => Ok(value),
+            reply => unreachable!("unexpected reply to Increment: {reply:?}"),
+        }
+    }
+
+    /// Retrieves the value of the named counter, which is 0 if it doesn't exist.
+    pub async fn get(&self, key: &str) -> Result<u64, ActorError> {
+        match self
+            .shard(key)
+            .ask(KeyedCommand::Get(key.to_string()))
+            .await?
+        {
+            Ok(KeyedReply::Value(value)) => Ok(value),
+            reply => unreachable!("unexpected reply to Get: {reply:?}"),
+        }
+    }
+
+    /// Removes the named counter.
+    ///
+    /// # Returns
+    /// The counter's value before it was removed, or `None` if it didn't exist.
+    pub async fn delete(&self, key: &str) -> Result<Option<u64>, ActorError> {
+        match self
+            .shard(key)
+            .ask(KeyedCommand::Delete(key.to_string()))
+            .await?
+        {
+            Ok(KeyedReply::Removed(value)) => Ok(value),
+            reply => unreachable!("unexpected reply to Delete: {reply:?}"),
+        }
+    }
+
+    /// Retrieves every counter from every shard, sorted by key.
+    ///
+    /// Each shard is read separately, so the result isn't a single point-in-time view.
+    pub async fn list(&self) -> Result<BTreeMap<String, u64>, ActorError> {
+        let mut all = BTreeMap::new();
+        for shard in &self.shards {
+            match shard.ask(KeyedCommand::List).await? {
+                Ok(KeyedReply::Entries(entries)) => all.extend(entries),
+                reply => unreachable!("unexpected reply to List: {reply:?}"),
+            }
+        }
+        Ok(all)
+    }
+
+    /// Registers every shard in `registry`, as `name/0`, `name/1` and so on, so they show
+    /// up in diagnostics.
+    ///
+    /// # Errors
+    /// Returns [`RegistryError::AlreadyRegistered`] if one of the names is taken. Shards
+    /// registered before it stay registered.
+    pub fn register(&self, registry: &Registry, name: &str) -> Result<(), RegistryError> {
+        for (index, shard) in self.shards.iter().enumerate() {
+            registry.register(format!("{name}/{index}"), shard.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Stops every shard once it has processed the commands already sent to it.
+    pub async fn shutdown(&self) {
+        for shard in &self.shards {
+            shard.shutdown().await;
+        }
+    }
+}
+
+/// Starts a keyed counter split across `shards` actors, holding at most
+/// [`DEFAULT_MAX_KEYS`] counters.
+///
+/// # Arguments
+/// * `shards` - The number of actor tasks to spread the keys across. Must be at least 1.
+///
+/// # Panics
+/// Panics if `shards` is 0.
+pub fn start_keyed(shards: usize) -> KeyedHandle {
+    start_keyed_with_limit(shards, DEFAULT_MAX_KEYS)
+}
+
+/// Starts a keyed counter split across `shards` actors, holding at most `max_keys`
+/// counters.
+///
+/// # Arguments
+/// * `shards` - The number of actor tasks to spread the keys across. Must be at least 1.
+/// * `max_keys` - How many counters may exist. Each shard holds an equal share, rounded
+///   up, so a new key can be refused before the total is reached if its shard is full.
+///
+/// # Panics
+/// Panics if `shards` is 0.
+pub fn start_keyed_with_limit(shards: usize, max_keys: usize) -> KeyedHandle {
+    assert!(shards > 0, "a keyed counter needs at least one shard");
+    let max_keys = max_keys.div_ceil(shards);
+    KeyedHandle {
+        shards: (0..shards)
+            .map(|_| actor::spawn(KeyedCounter { max_keys }, HashMap::new()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counters_are_independent() {
+        let counters = start_keyed(1);
+        assert_eq!(counters.increment("a").await, Ok(1));
+        assert_eq!(counters.increment("a").await, Ok(2));
+        assert_eq!(counters.increment("b").await, Ok(1));
+        assert_eq!(counters.get("a").await, Ok(2));
+        assert_eq!(counters.get("missing").await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let counters = start_keyed(1);
+        counters.increment("a").await.unwrap();
+        assert_eq!(counters.delete("a").await, Ok(Some(1)));
+        assert_eq!(counters.delete("a").await, Ok(None));
+        assert_eq!(counters.get("a").await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_list_merges_shards() {
+        let counters = start_keyed(4);
+        for key in ["tenant-1", "tenant-2", "tenant-3", "tenant-4", "tenant-5"] {
+            counters.increment(key).await.unwrap();
+        }
+        counters.increment("tenant-3").await.unwrap();
+
+        let all = counters.list().await.unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all["tenant-3"], 2);
+        assert_eq!(all.values().sum::<u64>(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_keys_always_route_to_the_same_shard() {
+        let counters = start_keyed(8);
+        for _ in 0..10 {
+            counters.increment("hot").await.unwrap();
+        }
+        assert_eq!(counters.get("hot").await, Ok(10));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_increments_across_shards() {
+        let counters = start_keyed(4);
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..100 {
+            let counters = counters.clone();
+            tasks.spawn(async move { counters.increment(&format!("key-{}", i % 10)).await });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap().unwrap();
+        }
+
+        let all = counters.list().await.unwrap();
+        assert_eq!(all.len(), 10);
+        assert!(all.values().all(|value| *value == 10));
+    }
+
+    #[tokio::test]
+    async fn test_register_lists_every_shard() {
+        let registry = Registry::new();
+        let counters = start_keyed(3);
+        counters.register(&registry, "tenants").unwrap();
+
+        let keys: Vec<_> = registry.list().into_iter().map(|actor| actor.key).collect();
+        assert_eq!(keys, ["tenants/0", "tenants/1", "tenants/2"]);
+        let shard: ActorRef<KeyedCounter> = registry.lookup("tenants/1").unwrap();
+        assert_eq!(
+            shard.ask(KeyedCommand::Get("a".to_string())).await,
+            Ok(Ok(KeyedReply::Value(0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_keys_are_limited() {
+        let counters = start_keyed_with_limit(1, 2);
+        counters.increment("a").await.unwrap();
+        counters.increment("b").await.unwrap();
+        assert_eq!(counters.increment("c").await, Err(KeyedError::TooManyKeys));
+        // Existing counters still count, and deleting one makes room.
+        assert_eq!(counters.increment("a").await, Ok(2));
+        counters.delete("b").await.unwrap();
+        assert_eq!(counters.increment("c").await, Ok(1));
+
+        assert_eq!(counters.increment("").await, Err(KeyedError::InvalidKey));
+        let long = "k".repeat(MAX_KEY_LENGTH + 1);
+        assert_eq!(counters.increment(&long).await, Err(KeyedError::InvalidKey));
+    }
+
+    #[test]
+    fn test_increment_stops_at_the_maximum() {
+        let actor = KeyedCounter { max_keys: 1 };
+        let mut counters = HashMap::from([("a".to_string(), u64::MAX)]);
+        assert_eq!(
+            actor.increment(&mut counters, "a".to_string()),
+            Err(KeyedError::Overflow { current: u64::MAX })
+        );
+        assert_eq!(counters["a"], u64::MAX);
+    }
+}
@@ -1,38 +1,647 @@
+pub mod actor;
+pub mod keyed;
+pub mod mailbox;
+pub mod persistence;
+pub mod rate_limit;
+pub mod registry;
+pub mod replication;
+pub mod supervisor;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+
+pub use actor::{
+    spawn, spawn_with, Actor, ActorError, ActorMetrics, ActorRef, ActorStatus, CommandMetrics,
+    Introspection, Priority,
+};
+pub use keyed::{
+    start_keyed, start_keyed_with_limit, KeyedCommand, KeyedError, KeyedHandle,
+};
+pub use mailbox::{MailboxConfig, OverflowPolicy};
+pub use persistence::{FsyncPolicy, Journal, PersistenceConfig};
+pub use rate_limit::{start_rate_limiter, RateDecision, RateLimitConfig, RateLimiter};
+#[cfg(feature = "tower")]
+pub use rate_limit::{LimiterErrorPolicy, RateLimitLayer};
+pub use registry::{ActorInfo, Registrable, Registry, RegistryError};
+pub use replication::{
+    start_replicated, GCounter, NodeCount, ReplicatedHandle, ReplicationConfig,
+};
+pub use supervisor::{Supervisor, SupervisorConfig, SupervisorEvent};
+
 /// Commands that can be sent to the shared state actor.
 /// This enum represents the different operations that can be performed on the actor's internal state.
+///
+/// Every command is answered with the counter's value after it was applied. Changes that
+/// would take the counter below 0 or past `u64::MAX` are refused with
+/// [`CounterError::Overflow`], unless they saturate.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum SharedStateCommand {
     /// Increments the internal counter by 1.
     Increment,
+    /// Adds the given amount, which may be negative, to the internal counter.
+    Add(i64),
+    /// Adds the given amount, stopping at 0 or `u64::MAX` instead of failing.
+    SaturatingAdd(i64),
+    /// Sets the internal counter back to 0.
+    Reset,
+    /// Sets the internal counter to the given value.
+    Set(u64),
+    /// Sets the internal counter to `new`, but only if it currently holds `expected`.
+    /// Fails with [`CounterError::Mismatch`] otherwise.
+    CompareAndSet { expected: u64, new: u64 },
     /// Retrieves the current value of the counter.
-    /// The response is sent back through the provided oneshot channel.
-    Get(tokio::sync::oneshot::Sender<u64>),
+    Get,
+}
+
+/// Why a counter command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterError {
+    /// The command never reached the actor, or it didn't answer.
+    Actor(ActorError),
+    /// The change would have taken the counter out of range, so it was left at `current`.
+    Overflow { current: u64 },
+    /// A `CompareAndSet` found `current` rather than the expected value, and did nothing.
+    Mismatch { current: u64 },
+    /// The change couldn't be recorded in the counter's journal, so it wasn't made.
+    Journal(std::io::ErrorKind),
+}
+
+impl std::fmt::Display for CounterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CounterError::Actor(err) => err.fmt(f),
+            CounterError::Overflow { current } => {
+                write!(f, "the change would overflow the counter, which is {current}")
+            }
+            CounterError::Mismatch { current } => {
+                write!(f, "the counter is {current}, not the expected value")
+            }
+            CounterError::Journal(kind) => {
+                write!(f, "the change could not be written to the journal: {kind}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CounterError {}
+
+impl From<ActorError> for CounterError {
+    fn from(err: ActorError) -> Self {
+        CounterError::Actor(err)
+    }
+}
+
+/// The shared state actor: a single `u64` counter.
+///
+/// The counter can optionally record every change in a [`Journal`], so it survives restarts.
+#[derive(Debug, Default)]
+pub struct Counter {
+    journal: Option<Journal<u64, SharedStateCommand>>,
+}
+
+impl Counter {
+    /// Works out the counter's value after `command`, without changing anything.
+    fn evaluate(counter: u64, command: &SharedStateCommand) -> Result<u64, CounterError> {
+        let overflow = CounterError::Overflow { current: counter };
+        match *command {
+            SharedStateCommand::Increment => counter.checked_add(1).ok_or(overflow),
+            SharedStateCommand::Add(amount) => counter.checked_add_signed(amount).ok_or(overflow),
+            SharedStateCommand::SaturatingAdd(amount) => Ok(counter.saturating_add_signed(amount)),
+            SharedStateCommand::Reset => Ok(0),
+            SharedStateCommand::Set(value) => Ok(value),
+            SharedStateCommand::CompareAndSet { expected, new } if expected == counter => Ok(new),
+            SharedStateCommand::CompareAndSet { .. } => {
+                Err(CounterError::Mismatch { current: counter })
+            }
+            SharedStateCommand::Get => Ok(counter),
+        }
+    }
+
+    /// Applies a command, recording it in the journal first if it changes anything.
+    fn apply(
+        &mut self,
+        counter: &mut u64,
+        command: SharedStateCommand,
+    ) -> Result<u64, CounterError> {
+        let value = Self::evaluate(*counter, &command)?;
+
+        // Write-ahead: the change is on disk before it's applied. If it can't be recorded,
+        // it's refused, so the caller never sees a value that would be lost on restart.
+        // Failed commands and reads change nothing, so they aren't logged.
+        if let Some(journal) = &mut self.journal
+            && command != SharedStateCommand::Get
+        {
+            let failed = |e: std::io::Error| CounterError::Journal(e.kind());
+            if journal.snapshot_due() {
+                journal.snapshot(counter).map_err(failed)?;
+            }
+            journal.append(&command).map_err(failed)?;
+        }
+
+        *counter = value;
+        Ok(value)
+    }
+}
+
+impl Actor for Counter {
+    type State = u64;
+    type Message = SharedStateCommand;
+    /// The value of the counter after the command was applied, or why it wasn't.
+    type Reply = Result<u64, CounterError>;
+
+    fn handle(&mut self, counter: &mut u64, command: SharedStateCommand) -> Self::Reply {
+        self.apply(counter, command)
+    }
+
+    /// Applies every change in the batch, in order. Each change is answered with the
+    /// value it produced, and every `Get` with the value after the whole batch.
+    fn handle_batch(
+        &mut self,
+        counter: &mut u64,
+        commands: Vec<SharedStateCommand>,
+    ) -> Vec<Self::Reply> {
+        let mut replies = Vec::with_capacity(commands.len());
+        let mut gets = Vec::new();
+        for command in commands {
+            if command == SharedStateCommand::Get {
+                gets.push(replies.len());
+                replies.push(Ok(0));
+            } else {
+                replies.push(self.apply(counter, command));
+            }
+        }
+        for index in gets {
+            replies[index] = Ok(*counter);
+        }
+        replies
+    }
+
+    fn message_name(command: &SharedStateCommand) -> &'static str {
+        match command {
+            SharedStateCommand::Increment => "increment",
+            SharedStateCommand::Add(_) => "add",
+            SharedStateCommand::SaturatingAdd(_) => "saturating_add",
+            SharedStateCommand::Reset => "reset",
+            SharedStateCommand::Set(_) => "set",
+            SharedStateCommand::CompareAndSet { .. } => "compare_and_set",
+            SharedStateCommand::Get => "get",
+        }
+    }
+
+    fn stopped(&mut self, counter: &mut u64) {
+        // Leave a fresh snapshot behind, so the next start has no log to replay.
+        if let Some(journal) = &mut self.journal
+            && let Err(e) = journal.snapshot(counter)
+        {
+            eprintln!("Failed to write the counter snapshot on shutdown: {e}");
+        }
+    }
+}
+
+/// Several counter commands applied inside the actor, with no other command in between.
+///
+/// Handed to the closure passed to [`ActorHandle::transaction`].
+#[derive(Debug)]
+pub struct CounterTransaction<'a> {
+    counter: &'a mut Counter,
+    value: &'a mut u64,
+}
+
+impl CounterTransaction<'_> {
+    /// The counter's current value, including the changes made so far.
+    pub fn value(&self) -> u64 {
+        *self.value
+    }
+
+    /// Applies a command, exactly as if it had been sent to the actor.
+    ///
+    /// # Returns
+    /// The counter's value after the command was applied.
+    ///
+    /// # Errors
+    /// Returns the command's error if it was refused, leaving the counter unchanged.
+    pub fn apply(&mut self, command: SharedStateCommand) -> Result<u64, CounterError> {
+        self.counter.handle(self.value, command)
+    }
+
+    /// Applies every command in order, or none of them if any would fail.
+    ///
+    /// # Returns
+    /// The value after each command.
+    ///
+    /// # Errors
+    /// Returns the error from the first command that would fail, leaving the counter
+    /// unchanged.
+    pub fn apply_all(
+        &mut self,
+        commands: Vec<SharedStateCommand>,
+    ) -> Result<Vec<u64>, CounterError> {
+        // Try the whole batch out first, so nothing is applied or journaled if it fails.
+        let mut value = *self.value;
+        for command in &commands {
+            value = Counter::evaluate(value, command)?;
+        }
+        commands
+            .into_iter()
+            .map(|command| self.apply(command))
+            .collect()
+    }
 }
 
-/// Starts a new shared state actor and returns a sender to communicate with it.
+/// A cloneable handle to a running shared state actor.
+///
+/// The handle hides the channel and reply wiring behind plain methods. It is cheap to
+/// clone, so it can be stored directly in an axum `Extension` or `State`, or as a field
+/// of a tonic service.
+#[derive(Clone, Debug)]
+pub struct ActorHandle {
+    actor: ActorRef<Counter>,
+}
+
+impl ActorHandle {
+    /// Returns a copy of this handle that gives up on every call after `timeout`.
+    ///
+    /// Use this to set a default timeout when the actor is started, or for a single call.
+    pub fn with_timeout(&self, timeout: std::time::Duration) -> Self {
+        Self {
+            actor: self.actor.with_timeout(timeout),
+        }
+    }
+
+    /// Returns a copy of this handle whose calls all give up at `deadline`.
+    ///
+    /// Use this to carry an incoming request's deadline through to the actor.
+    pub fn with_deadline(&self, deadline: tokio::time::Instant) -> Self {
+        Self {
+            actor: self.actor.with_deadline(deadline),
+        }
+    }
+
+    /// Returns a copy of this handle whose commands go in the given mailbox lane.
+    ///
+    /// A high-priority handle is useful for reads that must stay fast while the counter
+    /// is flooded with increments. Its reads may not include increments still queued in
+    /// the normal lane, even ones sent earlier from the same task.
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            actor: self.actor.with_priority(priority),
+        }
+    }
+
+    /// Checks that the actor is running and responsive, ahead of any queued commands.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply in time.
+    pub async fn ping(&self) -> Result<(), ActorError> {
+        self.actor.ping().await
+    }
+
+    /// Stops the actor once it has processed every command already sent to it.
+    ///
+    /// New commands are refused straight away. If the counter is persisted, a final
+    /// snapshot is written before this returns.
+    pub async fn shutdown(&self) {
+        self.actor.shutdown().await;
+    }
+
+    /// Increments the counter by 1, without waiting for the actor to process it.
+    ///
+    /// Errors are ignored; use [`ActorHandle::try_increment`] to find out if the actor
+    /// is no longer running.
+    pub async fn increment(&self) {
+        let _ = self.try_increment().await;
+    }
+
+    /// Adds `amount` to the counter, without waiting for the actor to process it.
+    ///
+    /// A negative amount subtracts. If the result would be out of range, the counter
+    /// is left as it was.
+    pub async fn add(&self, amount: i64) {
+        let _ = self.try_add(amount).await;
+    }
+
+    /// Sets the counter back to 0, without waiting for the actor to process it.
+    pub async fn reset(&self) {
+        let _ = self.try_reset().await;
+    }
+
+    /// Retrieves the current value of the counter.
+    ///
+    /// # Returns
+    /// The current value of the counter, or 0 if the actor is no longer running.
+    /// Use [`ActorHandle::try_get`] to tell the two apart.
+    pub async fn get(&self) -> u64 {
+        self.try_get().await.unwrap_or(0)
+    }
+
+    /// Subscribes to the counter, to be told about every change without polling.
+    ///
+    /// The receiver starts with the current value. It stays subscribed across supervisor
+    /// restarts, and is closed when the actor stops.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub async fn subscribe(&self) -> Result<tokio::sync::watch::Receiver<u64>, ActorError> {
+        self.actor.subscribe().await
+    }
+
+    /// Increments the counter by 1, reporting whether the command could be delivered.
+    pub async fn try_increment(&self) -> Result<(), ActorError> {
+        self.actor.tell(SharedStateCommand::Increment).await
+    }
+
+    /// Adds `amount` to the counter, reporting whether the command could be delivered.
+    pub async fn try_add(&self, amount: i64) -> Result<(), ActorError> {
+        self.actor.tell(SharedStateCommand::Add(amount)).await
+    }
+
+    /// Sets the counter back to 0, reporting whether the command could be delivered.
+    pub async fn try_reset(&self) -> Result<(), ActorError> {
+        self.actor.tell(SharedStateCommand::Reset).await
+    }
+
+    /// Retrieves the current value of the counter.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub async fn try_get(&self) -> Result<u64, ActorError> {
+        match self.actor.ask(SharedStateCommand::Get).await? {
+            Ok(value) => Ok(value),
+            Err(err) => unreachable!("reading the counter can't fail: {err}"),
+        }
+    }
+
+    /// Applies any command and waits for the outcome.
+    ///
+    /// # Returns
+    /// The counter's value after the command was applied.
+    ///
+    /// # Errors
+    /// Returns [`CounterError::Actor`] if the actor is not running or did not reply, or
+    /// the command's own error if it was refused.
+    pub async fn apply(&self, command: SharedStateCommand) -> Result<u64, CounterError> {
+        self.actor.ask(command).await?
+    }
+
+    /// Increments the counter by 1 and returns the value this increment produced.
+    ///
+    /// Unlike an increment followed by a get, the value can't include anyone else's
+    /// increments made in between.
+    ///
+    /// # Errors
+    /// Returns a [`CounterError`] if the actor is unavailable or the counter is at `u64::MAX`.
+    pub async fn increment_and_get(&self) -> Result<u64, CounterError> {
+        self.apply(SharedStateCommand::Increment).await
+    }
+
+    /// Sets the counter to `new` if it currently holds `expected`.
+    ///
+    /// # Returns
+    /// The new value, `new`.
+    ///
+    /// # Errors
+    /// Returns [`CounterError::Mismatch`] with the actual value if it wasn't `expected`,
+    /// or [`CounterError::Actor`] if the actor is unavailable.
+    pub async fn compare_and_set(&self, expected: u64, new: u64) -> Result<u64, CounterError> {
+        self.apply(SharedStateCommand::CompareAndSet { expected, new })
+            .await
+    }
+
+    /// Runs `work` inside the actor, so every command it applies happens together, with
+    /// no other caller's commands in between. Returns whatever `work` returns.
+    ///
+    /// `work` runs on the actor's task and holds up every other caller, so keep it short
+    /// and don't block in it.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub async fn transaction<R: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut CounterTransaction<'_>) -> R + Send + 'static,
+    ) -> Result<R, ActorError> {
+        self.actor
+            .transaction(|counter, value| work(&mut CounterTransaction { counter, value }))
+            .await
+    }
+
+    /// Applies every command together, or none of them if any would fail.
+    ///
+    /// # Returns
+    /// The value after each command.
+    ///
+    /// # Errors
+    /// Returns [`CounterError::Actor`] if the actor is unavailable, or the error from the
+    /// first command that would fail.
+    pub async fn apply_all(
+        &self,
+        commands: Vec<SharedStateCommand>,
+    ) -> Result<Vec<u64>, CounterError> {
+        self.transaction(|transaction| transaction.apply_all(commands))
+            .await?
+    }
+
+    /// Reads the actor's mailbox depth, processed count and per-command latencies.
+    ///
+    /// Commands are named after their [`SharedStateCommand`] variant in snake case, such
+    /// as `increment` and `compare_and_set`.
+    pub fn metrics(&self) -> ActorMetrics {
+        self.actor.metrics()
+    }
+
+    /// Takes a snapshot of the counter: its value, uptime, mailbox depth and metrics.
+    ///
+    /// The snapshot reflects every command this handle sent before.
+    pub async fn inspect(&self) -> Result<Introspection<u64>, ActorError> {
+        self.actor.inspect().await
+    }
+
+    /// The underlying actor handle, for generic code that works with any actor.
+    pub fn actor_ref(&self) -> &ActorRef<Counter> {
+        &self.actor
+    }
+
+    /// Returns a handle to the same actor for code that isn't async.
+    pub fn blocking(&self) -> BlockingHandle {
+        BlockingHandle {
+            actor: self.actor.clone(),
+        }
+    }
+}
+
+impl Registrable for ActorHandle {
+    fn status(&self) -> ActorStatus {
+        self.actor.status()
+    }
+
+    fn metrics(&self) -> ActorMetrics {
+        self.actor.metrics()
+    }
+}
+
+/// A handle to a shared state actor for synchronous code.
+///
+/// Every method blocks the calling thread until the actor has taken the command, or
+/// answered it. Use it from plain threads, thread pools such as rayon, or inside
+/// [`tokio::task::spawn_blocking`]; from async code, use [`ActorHandle`] instead.
+///
+/// # Panics
+/// Every method panics if called from async code running on a Tokio runtime, since
+/// blocking there would stall the other tasks on the same thread. The exception is
+/// commands that don't wait for a reply, sent to a mailbox that never waits for room;
+/// they don't block, so they are let through.
+#[derive(Clone, Debug)]
+pub struct BlockingHandle {
+    actor: ActorRef<Counter>,
+}
+
+impl BlockingHandle {
+    /// Returns a copy of this handle whose calls are dropped unhandled, and fail with
+    /// [`ActorError::Timeout`], if they're still queued after `timeout`.
+    pub fn with_timeout(&self, timeout: std::time::Duration) -> Self {
+        Self {
+            actor: self.actor.with_timeout(timeout),
+        }
+    }
+
+    /// Returns the async handle to the same actor.
+    pub fn non_blocking(&self) -> ActorHandle {
+        ActorHandle {
+            actor: self.actor.clone(),
+        }
+    }
+
+    /// Increments the counter by 1, without waiting for the actor to process it.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the command could not be delivered.
+    pub fn increment(&self) -> Result<(), ActorError> {
+        self.actor.blocking_tell(SharedStateCommand::Increment)
+    }
+
+    /// Adds `amount`, which may be negative, without waiting for the actor to process it.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the command could not be delivered.
+    pub fn add(&self, amount: i64) -> Result<(), ActorError> {
+        self.actor.blocking_tell(SharedStateCommand::Add(amount))
+    }
+
+    /// Sets the counter back to 0, without waiting for the actor to process it.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the command could not be delivered.
+    pub fn reset(&self) -> Result<(), ActorError> {
+        self.actor.blocking_tell(SharedStateCommand::Reset)
+    }
+
+    /// Retrieves the current value of the counter.
+    ///
+    /// # Errors
+    /// Returns an [`ActorError`] if the actor is not running or did not reply.
+    pub fn get(&self) -> Result<u64, ActorError> {
+        match self.actor.blocking_ask(SharedStateCommand::Get)? {
+            Ok(value) => Ok(value),
+            Err(err) => unreachable!("reading the counter can't fail: {err}"),
+        }
+    }
+
+    /// Applies any command and waits for the outcome.
+    ///
+    /// # Returns
+    /// The counter's value after the command was applied.
+    ///
+    /// # Errors
+    /// Returns [`CounterError::Actor`] if the actor is not running or did not reply, or
+    /// the command's own error if it was refused.
+    pub fn apply(&self, command: SharedStateCommand) -> Result<u64, CounterError> {
+        self.actor.blocking_ask(command)?
+    }
+
+    /// Increments the counter by 1 and returns the value this increment produced.
+    ///
+    /// # Errors
+    /// Returns a [`CounterError`] if the actor is unavailable or the counter is at `u64::MAX`.
+    pub fn increment_and_get(&self) -> Result<u64, CounterError> {
+        self.apply(SharedStateCommand::Increment)
+    }
+
+    /// Sets the counter to `new` if it currently holds `expected`.
+    ///
+    /// # Errors
+    /// Returns [`CounterError::Mismatch`] with the actual value if it wasn't `expected`,
+    /// or [`CounterError::Actor`] if the actor is unavailable.
+    pub fn compare_and_set(&self, expected: u64, new: u64) -> Result<u64, CounterError> {
+        self.apply(SharedStateCommand::CompareAndSet { expected, new })
+    }
+}
+
+/// Starts a new shared state actor and returns a handle to communicate with it.
 /// 
 /// This function spawns a background task that maintains a counter state and processes
-/// commands sent through the returned sender. The actor processes commands sequentially,
+/// commands sent through the returned handle. The actor processes commands sequentially,
 /// ensuring thread-safe access to the shared state.
 ///
-/// Returns a `Sender` that can be used to send `SharedStateCommand`s to the actor.
-pub async fn start() -> tokio::sync::mpsc::Sender<SharedStateCommand> {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-    let mut counter: u64 = 0;
-
-    tokio::spawn(async move {
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                SharedStateCommand::Increment => {
-                    counter += 1;
-                }
-                SharedStateCommand::Get(resp_tx) => {
-                    let _ = resp_tx.send(counter);
-                }
-            }
-        }
-    });
+/// Returns an `ActorHandle` that can be used to operate on the actor's counter.
+pub async fn start() -> ActorHandle {
+    start_with_mailbox(MailboxConfig::default()).await
+}
+
+/// Starts a new shared state actor with a mailbox of the given size and overflow policy.
+///
+/// Setting `max_batch` above 1 lets the actor apply every command waiting in its mailbox
+/// in one go, answering all the `Get`s among them with the same value.
+///
+/// # Arguments
+/// * `mailbox` - How many commands can be queued, what happens when the queue is full,
+///   and how many commands are handled together
+///
+/// # Panics
+/// Panics if the configured capacity or batch size is 0.
+pub async fn start_with_mailbox(mailbox: MailboxConfig) -> ActorHandle {
+    ActorHandle {
+        actor: actor::spawn_with(Counter::default(), 0, mailbox),
+    }
+}
 
-    tx
+/// Starts a new shared state actor under a [`Supervisor`].
+///
+/// If the actor panics, it is restarted with the last value the counter reached, and the
+/// returned handle keeps working. Restarts are reported on the returned event receiver.
+///
+/// # Arguments
+/// * `config` - How the supervisor restarts the actor
+pub async fn start_supervised(
+    config: SupervisorConfig,
+) -> (ActorHandle, tokio::sync::broadcast::Receiver<SupervisorEvent>) {
+    let mut supervisor = Supervisor::new(config);
+    let actor = supervisor.supervise("counter", Counter::default, 0);
+    let events = supervisor.subscribe();
+    supervisor.start();
+    (ActorHandle { actor }, events)
+}
+
+/// Starts a new shared state actor that persists its counter to disk.
+///
+/// Any snapshot and log already in `config.dir` are replayed first, so the counter picks
+/// up where the last run left off.
+///
+/// # Errors
+/// Returns an error if the journal can't be opened or replayed.
+pub async fn start_persistent(config: PersistenceConfig) -> std::io::Result<ActorHandle> {
+    let recovered = tokio::task::spawn_blocking(move || Journal::open(config))
+        .await
+        .expect("journal recovery panicked")?;
+
+    let mut counter = recovered.snapshot.unwrap_or(0);
+    let mut actor = Counter::default();
+    for command in recovered.messages {
+        // Only commands that succeeded were logged, so replaying them can't fail.
+        let _ = actor.handle(&mut counter, command);
+    }
+    actor.journal = Some(recovered.journal);
+
+    Ok(ActorHandle {
+        actor: actor::spawn(actor, counter),
+    })
 }
 
 /// Retrieves the current counter value from the shared state actor.
@@ -41,17 +650,12 @@ pub async fn start() -> tokio::sync::mpsc::Sender<SharedStateCommand> {
 /// If the actor is no longer running or the response channel is closed, returns 0.
 ///
 /// # Arguments
-/// * `sender` - A reference to the sender channel connected to the actor
+/// * `handle` - A reference to the handle connected to the actor
 ///
 /// # Returns
 /// The current value of the counter, or 0 if the operation fails.
-pub async fn get_counter(sender: &tokio::sync::mpsc::Sender<SharedStateCommand>) -> u64 {
-    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
-    let cmd = SharedStateCommand::Get(resp_tx);
-    if sender.send(cmd).await.is_err() {
-        return 0;
-    }
-    resp_rx.await.unwrap_or(0)
+pub async fn get_counter(handle: &ActorHandle) -> u64 {
+    handle.get().await
 }
 
 /// Increments the counter in the shared state actor by 1.
@@ -60,16 +664,15 @@ pub async fn get_counter(sender: &tokio::sync::mpsc::Sender<SharedStateCommand>)
 /// meaning it doesn't wait for confirmation that the increment was processed.
 ///
 /// # Arguments
-/// * `sender` - A reference to the sender channel connected to the actor
-pub async fn increment_counter(sender: &tokio::sync::mpsc::Sender<SharedStateCommand>) {
-    let cmd = SharedStateCommand::Increment;
-    let _ = sender.send(cmd).await;
+/// * `handle` - A reference to the handle connected to the actor
+pub async fn increment_counter(handle: &ActorHandle) {
+    handle.increment().await;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::time::{sleep, Duration};
+    use tokio::time::Duration;
 
     #[tokio::test]
     async fn test_actor_starts_with_zero() {
@@ -169,7 +772,8 @@ mod tests {
             temp_sender
         };
         
-        sleep(Duration::from_millis(10)).await;
+        testing::settle(sender.actor_ref()).await.unwrap();
+        assert!(!sender.actor_ref().is_closed());
         
         let count = get_counter(&sender).await;
         assert_eq!(count, 1);
@@ -184,11 +788,363 @@ mod tests {
         let sender = start().await;
         increment_counter(&sender).await;
         
+        let stopped = testing::when_stopped(sender.actor_ref());
         drop(sender);
-        sleep(Duration::from_millis(10)).await;
+        stopped.await;
         
         let sender2 = start().await;
         let count = get_counter(&sender2).await;
         assert_eq!(count, 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_handle_add_and_reset() {
+        let handle = start().await;
+
+        handle.add(10).await;
+        handle.increment().await;
+        assert_eq!(handle.get().await, 11);
+
+        handle.reset().await;
+        assert_eq!(handle.get().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cloned_handles_share_state() {
+        let handle = start().await;
+        let clone = handle.clone();
+
+        handle.increment().await;
+        clone.add(2).await;
+        assert_eq!(handle.get().await, 3);
+        assert_eq!(clone.get().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_try_variants_succeed_on_running_actor() {
+        let handle = start().await;
+
+        assert_eq!(handle.try_increment().await, Ok(()));
+        assert_eq!(handle.try_add(2).await, Ok(()));
+        assert_eq!(handle.try_get().await, Ok(3));
+        assert_eq!(handle.try_reset().await, Ok(()));
+        assert_eq!(handle.try_get().await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_handle_with_timeout_still_answers() {
+        let handle = start().await.with_timeout(Duration::from_secs(1));
+
+        handle.increment().await;
+        assert_eq!(handle.try_get().await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_handle_with_expired_deadline_times_out() {
+        let handle = start().await;
+        let late = handle.with_deadline(tokio::time::Instant::now());
+
+        assert_eq!(late.try_get().await, Err(ActorError::Timeout));
+        assert_eq!(handle.try_get().await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_supervised_counter() {
+        let (handle, _events) = start_supervised(SupervisorConfig::default()).await;
+
+        handle.increment().await;
+        handle.add(4).await;
+        assert_eq!(handle.try_get().await, Ok(5));
+    }
+
+    #[tokio::test]
+    async fn test_persistent_counter_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PersistenceConfig {
+            snapshot_every: 2,
+            ..PersistenceConfig::new(dir.path())
+        };
+
+        let handle = start_persistent(config.clone()).await.unwrap();
+        handle.increment().await;
+        handle.add(10).await;
+        handle.increment().await;
+        assert_eq!(handle.try_get().await, Ok(12));
+        drop(handle);
+
+        let handle = start_persistent(config).await.unwrap();
+        assert_eq!(handle.try_get().await, Ok(12));
+        handle.reset().await;
+        assert_eq!(handle.try_get().await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_processes_queued_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = start_persistent(PersistenceConfig::new(dir.path()))
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            handle.increment().await;
+        }
+        handle.shutdown().await;
+        assert_eq!(handle.try_get().await, Err(ActorError::Closed));
+
+        // The final snapshot holds everything, so there's no log left to replay.
+        let log = std::fs::read(dir.path().join("journal.log")).unwrap();
+        assert!(log.is_empty());
+        let handle = start_persistent(PersistenceConfig::new(dir.path()))
+            .await
+            .unwrap();
+        assert_eq!(handle.try_get().await, Ok(5));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_to_counter() {
+        let handle = start().await;
+        handle.add(2).await;
+
+        let mut changes = handle.subscribe().await.unwrap();
+        assert_eq!(*changes.borrow_and_update(), 2);
+
+        handle.increment().await;
+        changes.changed().await.unwrap();
+        assert_eq!(*changes.borrow_and_update(), 3);
+
+        // Reading the counter doesn't count as a change.
+        handle.get().await;
+        assert!(!changes.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_each_command() {
+        let handle = start().await;
+        handle.increment().await;
+        handle.increment().await;
+        handle.add(5).await;
+        assert_eq!(handle.try_get().await, Ok(7));
+
+        let metrics = handle.metrics();
+        assert_eq!(metrics.processed, 4);
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(metrics.mailbox, MailboxConfig::default());
+        assert_eq!(metrics.commands["increment"].count, 2);
+        assert_eq!(metrics.commands["add"].count, 1);
+        assert_eq!(metrics.commands["get"].count, 1);
+        assert!(!metrics.commands.contains_key("reset"));
+    }
+
+    #[tokio::test]
+    async fn test_inspect_sees_sent_commands() {
+        let handle = start().await;
+        handle.add(3).await;
+        handle.increment().await;
+
+        let snapshot = handle.inspect().await.unwrap();
+        assert_eq!(snapshot.state, 4);
+        assert_eq!(snapshot.metrics.processed, 2);
+        assert_eq!(snapshot.metrics.queue_depth, 0);
+    }
+
+    #[tokio::test]
+    async fn test_priority_reads_skip_queued_increments() {
+        let handle = start().await;
+        handle.add(1).await;
+        let reader = handle.with_priority(Priority::High);
+        for _ in 0..10 {
+            handle.increment().await;
+        }
+
+        // The read may overtake the queued increments, but never sees a partial command.
+        let value = reader.try_get().await.unwrap();
+        assert!((0..=11).contains(&value));
+        assert_eq!(handle.try_get().await, Ok(11));
+        assert_eq!(handle.ping().await, Ok(()));
+    }
+
+    #[test]
+    fn test_batch_answers_every_get_with_the_final_value() {
+        use SharedStateCommand::*;
+        let mut counter = 5;
+        let commands = vec![Get, Increment, Add(3), Get];
+        let replies = Counter::default().handle_batch(&mut counter, commands);
+        assert_eq!(replies, vec![Ok(9), Ok(6), Ok(9), Ok(9)]);
+        assert_eq!(counter, 9);
+    }
+
+    #[tokio::test]
+    async fn test_batched_counter_under_concurrent_load() {
+        let handle = start_with_mailbox(MailboxConfig {
+            max_batch: 16,
+            ..MailboxConfig::default()
+        })
+        .await;
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..100 {
+            let handle = handle.clone();
+            tasks.spawn(async move {
+                handle.try_increment().await.unwrap();
+                handle.try_get().await.unwrap()
+            });
+        }
+        while let Some(seen) = tasks.join_next().await {
+            assert!(seen.unwrap() >= 1);
+        }
+        assert_eq!(handle.try_get().await, Ok(100));
+        assert_eq!(handle.metrics().commands["increment"].count, 100);
+    }
+
+    #[tokio::test]
+    async fn test_commands_return_the_new_value() {
+        let handle = start().await;
+        assert_eq!(handle.increment_and_get().await, Ok(1));
+        assert_eq!(handle.apply(SharedStateCommand::Add(10)).await, Ok(11));
+        assert_eq!(handle.apply(SharedStateCommand::Add(-4)).await, Ok(7));
+        assert_eq!(handle.apply(SharedStateCommand::Set(42)).await, Ok(42));
+        assert_eq!(handle.apply(SharedStateCommand::Reset).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_changes_are_refused_or_saturate() {
+        let handle = start().await;
+        handle.apply(SharedStateCommand::Set(3)).await.unwrap();
+        assert_eq!(
+            handle.apply(SharedStateCommand::Add(-4)).await,
+            Err(CounterError::Overflow { current: 3 })
+        );
+        assert_eq!(handle.apply(SharedStateCommand::SaturatingAdd(-4)).await, Ok(0));
+
+        handle.apply(SharedStateCommand::Set(u64::MAX)).await.unwrap();
+        assert_eq!(
+            handle.increment_and_get().await,
+            Err(CounterError::Overflow { current: u64::MAX })
+        );
+        assert_eq!(
+            handle.apply(SharedStateCommand::SaturatingAdd(1)).await,
+            Ok(u64::MAX)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_set() {
+        let handle = start().await;
+        handle.add(5).await;
+        assert_eq!(handle.compare_and_set(5, 8).await, Ok(8));
+        assert_eq!(
+            handle.compare_and_set(5, 9).await,
+            Err(CounterError::Mismatch { current: 8 })
+        );
+        assert_eq!(handle.try_get().await, Ok(8));
+    }
+
+    #[tokio::test]
+    async fn test_refused_commands_are_not_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = start_persistent(PersistenceConfig::new(dir.path()))
+            .await
+            .unwrap();
+        handle.apply(SharedStateCommand::Set(2)).await.unwrap();
+        let _ = handle.apply(SharedStateCommand::Add(-3)).await;
+        let _ = handle.compare_and_set(7, 0).await;
+
+        // Read the log while the actor is still running, before its final snapshot.
+        let config = PersistenceConfig::new(dir.path());
+        let recovered = Journal::<u64, SharedStateCommand>::open(config).unwrap();
+        assert_eq!(recovered.messages, vec![SharedStateCommand::Set(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_journal_errors_are_returned_to_the_caller() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PersistenceConfig {
+            dir: dir.path().join("counter"),
+            snapshot_every: 1,
+            ..PersistenceConfig::new(dir.path())
+        };
+        let handle = start_persistent(config.clone()).await.unwrap();
+        assert_eq!(handle.increment_and_get().await, Ok(1));
+
+        // The next change is due a snapshot, which can't be written without the directory.
+        std::fs::remove_dir_all(&config.dir).unwrap();
+        assert_eq!(
+            handle.increment_and_get().await,
+            Err(CounterError::Journal(std::io::ErrorKind::NotFound))
+        );
+        assert_eq!(handle.try_get().await, Ok(1));
+
+        std::fs::create_dir(&config.dir).unwrap();
+        assert_eq!(handle.increment_and_get().await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_sees_only_its_own_changes() {
+        let handle = start().await;
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let handle = handle.clone();
+            tasks.spawn(async move {
+                handle
+                    .transaction(|counter| {
+                        let before = counter.value();
+                        let after = counter.apply(SharedStateCommand::Add(2)).unwrap();
+                        (before, after)
+                    })
+                    .await
+                    .unwrap()
+            });
+        }
+        let mut seen = Vec::new();
+        while let Some(result) = tasks.join_next().await {
+            let (before, after) = result.unwrap();
+            assert_eq!(after, before + 2);
+            seen.push(after);
+        }
+        seen.sort();
+        assert_eq!(seen, (1..=20).map(|i| i * 2).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_apply_all_is_all_or_nothing() {
+        use SharedStateCommand::*;
+        let handle = start().await;
+        assert_eq!(
+            handle.apply_all(vec![Set(5), Add(-2), Increment]).await,
+            Ok(vec![5, 3, 4])
+        );
+        assert_eq!(
+            handle.apply_all(vec![Increment, Add(-10), Increment]).await,
+            Err(CounterError::Overflow { current: 5 })
+        );
+        assert_eq!(handle.try_get().await, Ok(4));
+    }
+
+    #[test]
+    fn test_blocking_handle_from_worker_threads() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = runtime.block_on(start()).blocking();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        handle.increment().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(handle.get(), Ok(100));
+        assert_eq!(handle.compare_and_set(100, 0), Ok(0));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot block the current thread from within a runtime")]
+    async fn test_blocking_handle_panics_in_async_code() {
+        let handle = start().await.blocking();
+        let _ = handle.get();
+    }
+}
+
@@ -0,0 +1,506 @@
+//! A bounded, multi-producer single-consumer queue for actor mailboxes.
+//!
+//! This works like `tokio::sync::mpsc::channel`, but lets you choose what happens when
+//! the queue is full: wait for room (the channel's behavior), reject the new message, or
+//! make room by discarding the oldest one. That's the backpressure choice from the
+//! channels chapter, made per actor.
+//!
+//! Waiting and rejecting are done by a tokio channel. A tokio channel can't give up a
+//! message once it's queued, so discarding uses a queue of its own.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// What a sender does when the mailbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the actor makes room. Slow actors slow down their callers.
+    Wait,
+    /// Refuse the new message straight away, with [`ActorError::Full`](crate::ActorError::Full).
+    Reject,
+    /// Accept the new message and discard the oldest queued one. Callers waiting for a
+    /// reply to a discarded message get
+    /// [`ActorError::ReplyDropped`](crate::ActorError::ReplyDropped).
+    ///
+    /// Only ordinary messages are discarded, never the actor's own requests such as
+    /// subscribing or inspecting. If nothing queued can be discarded, the new message is
+    /// refused as with [`OverflowPolicy::Reject`].
+    DropOldest,
+}
+
+/// The size of an actor's mailbox, and what happens when it fills up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxConfig {
+    /// The most messages that can be queued at once. Must be at least 1.
+    pub capacity: usize,
+    /// What happens to messages sent while the mailbox is full.
+    pub overflow: OverflowPolicy,
+    /// The most messages the actor takes from the mailbox and handles together, with
+    /// [`Actor::handle_batch`](crate::Actor::handle_batch). 1 handles them one at a time.
+    /// Must be at least 1.
+    pub max_batch: usize,
+}
+
+impl Default for MailboxConfig {
+    /// [`MAILBOX_CAPACITY`](crate::actor::MAILBOX_CAPACITY) messages, waiting for room
+    /// when full, handled one at a time.
+    fn default() -> Self {
+        Self {
+            capacity: crate::actor::MAILBOX_CAPACITY,
+            overflow: OverflowPolicy::Wait,
+            max_batch: 1,
+        }
+    }
+}
+
+/// Tells [`OverflowPolicy::DropOldest`] which queued messages it may discard.
+pub(crate) trait Discard {
+    /// Returns `true` if the message can be thrown away to make room for a newer one.
+    fn may_discard(&self) -> bool;
+}
+
+/// Why a message could not be queued. The message is handed back.
+pub(crate) enum SendError<T> {
+    /// The receiver has closed the mailbox or gone away.
+    Closed(T),
+    /// The mailbox is full, and the policy refused the message.
+    Full(T),
+}
+
+impl<T> std::fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Closed(_) => write!(f, "Closed(..)"),
+            SendError::Full(_) => write!(f, "Full(..)"),
+        }
+    }
+}
+
+/// How many messages didn't make it into the mailbox, or back out of it.
+#[derive(Default)]
+struct Losses {
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// The queue behind [`OverflowPolicy::DropOldest`].
+struct Queue<T> {
+    items: VecDeque<T>,
+    /// No more messages are accepted.
+    closed: bool,
+    senders: usize,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    capacity: usize,
+    /// Wakes the receiver when a message arrives or the last sender goes away.
+    item_ready: Notify,
+}
+
+/// The sending half of a mailbox. Cloning it adds another sender.
+pub(crate) struct Sender<T> {
+    lane: SenderLane<T>,
+    config: MailboxConfig,
+    losses: Arc<Losses>,
+}
+
+enum SenderLane<T> {
+    Channel(mpsc::Sender<T>),
+    Queue(Arc<Shared<T>>),
+}
+
+/// The receiving half of a mailbox.
+pub(crate) struct Receiver<T> {
+    lane: ReceiverLane<T>,
+}
+
+enum ReceiverLane<T> {
+    Channel(mpsc::Receiver<T>),
+    Queue(Arc<Shared<T>>),
+}
+
+/// Creates a mailbox with the given capacity and overflow policy.
+///
+/// # Panics
+/// Panics if the capacity or batch size is 0.
+pub(crate) fn channel<T>(config: MailboxConfig) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        config.capacity > 0,
+        "a mailbox needs room for at least one message"
+    );
+    assert!(config.max_batch > 0, "a batch needs at least one message");
+    let (lane, receiver_lane) = match config.overflow {
+        OverflowPolicy::Wait | OverflowPolicy::Reject => {
+            let (tx, rx) = mpsc::channel(config.capacity);
+            (SenderLane::Channel(tx), ReceiverLane::Channel(rx))
+        }
+        OverflowPolicy::DropOldest => {
+            let shared = Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    items: VecDeque::with_capacity(config.capacity),
+                    closed: false,
+                    senders: 1,
+                }),
+                capacity: config.capacity,
+                item_ready: Notify::new(),
+            });
+            (
+                SenderLane::Queue(shared.clone()),
+                ReceiverLane::Queue(shared),
+            )
+        }
+    };
+    let sender = Sender {
+        lane,
+        config,
+        losses: Arc::default(),
+    };
+    (
+        sender,
+        Receiver {
+            lane: receiver_lane,
+        },
+    )
+}
+
+impl<T: Discard> Sender<T> {
+    /// Queues a message, applying the overflow policy if the mailbox is full.
+    pub(crate) async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match &self.lane {
+            SenderLane::Channel(tx) if self.config.overflow == OverflowPolicy::Wait => tx
+                .send(value)
+                .await
+                .map_err(|mpsc::error::SendError(value)| SendError::Closed(value)),
+            _ => self.try_send(value),
+        }
+    }
+
+    /// Like [`Sender::send`], but blocks the current thread while waiting for room.
+    ///
+    /// # Panics
+    /// Panics if the policy is [`OverflowPolicy::Wait`] and this is called from async
+    /// code, where blocking would stall the runtime.
+    pub(crate) fn blocking_send(&self, value: T) -> Result<(), SendError<T>> {
+        match &self.lane {
+            SenderLane::Channel(tx) if self.config.overflow == OverflowPolicy::Wait => tx
+                .blocking_send(value)
+                .map_err(|mpsc::error::SendError(value)| SendError::Closed(value)),
+            _ => self.try_send(value),
+        }
+    }
+
+    /// Queues a message if there's room, or applies a policy that doesn't wait.
+    fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        match &self.lane {
+            SenderLane::Channel(tx) => tx.try_send(value).map_err(|e| match e {
+                TrySendError::Full(value) => {
+                    self.losses.rejected.fetch_add(1, Ordering::Relaxed);
+                    SendError::Full(value)
+                }
+                TrySendError::Closed(value) => SendError::Closed(value),
+            }),
+            SenderLane::Queue(shared) => self.push(shared, value),
+        }
+    }
+
+    /// Queues a message, discarding the oldest one that may be discarded if there's no
+    /// room.
+    fn push(&self, shared: &Shared<T>, value: T) -> Result<(), SendError<T>> {
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(SendError::Closed(value));
+        }
+        let mut oldest = None;
+        if queue.items.len() >= shared.capacity {
+            let Some(index) = queue.items.iter().position(T::may_discard) else {
+                self.losses.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(SendError::Full(value));
+            };
+            oldest = queue.items.remove(index);
+            self.losses.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.items.push_back(value);
+        drop(queue);
+        // Dropped outside the lock, since dropping may wake a waiting caller.
+        drop(oldest);
+        shared.item_ready.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Sender<T> {
+    /// Returns `true` if the mailbox no longer accepts messages.
+    pub(crate) fn is_closed(&self) -> bool {
+        match &self.lane {
+            SenderLane::Channel(tx) => tx.is_closed(),
+            SenderLane::Queue(shared) => shared.queue.lock().unwrap().closed,
+        }
+    }
+
+    /// The number of messages waiting in the mailbox.
+    pub(crate) fn len(&self) -> usize {
+        match &self.lane {
+            SenderLane::Channel(tx) => tx.max_capacity() - tx.capacity(),
+            SenderLane::Queue(shared) => shared.queue.lock().unwrap().items.len(),
+        }
+    }
+
+    /// The mailbox's capacity and overflow policy.
+    pub(crate) fn config(&self) -> MailboxConfig {
+        self.config
+    }
+
+    /// How many messages were refused because the mailbox was full.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.losses.rejected.load(Ordering::Relaxed)
+    }
+
+    /// How many queued messages were discarded to make room for newer ones.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.losses.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let lane = match &self.lane {
+            SenderLane::Channel(tx) => SenderLane::Channel(tx.clone()),
+            SenderLane::Queue(shared) => {
+                shared.queue.lock().unwrap().senders += 1;
+                SenderLane::Queue(shared.clone())
+            }
+        };
+        Self {
+            lane,
+            config: self.config,
+            losses: self.losses.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let SenderLane::Queue(shared) = &self.lane else {
+            return;
+        };
+        let mut queue = shared.queue.lock().unwrap();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            drop(queue);
+            shared.item_ready.notify_one();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for at least one message, then moves up to `limit` of the messages that are
+    /// ready onto the end of `buffer`.
+    ///
+    /// # Returns
+    /// The number of messages received, which is 0 once the mailbox is empty and either
+    /// closed or without senders.
+    pub(crate) async fn recv_many(&mut self, buffer: &mut Vec<T>, limit: usize) -> usize {
+        let shared = match &mut self.lane {
+            ReceiverLane::Channel(rx) => return rx.recv_many(buffer, limit).await,
+            ReceiverLane::Queue(shared) => shared.clone(),
+        };
+        loop {
+            let item_ready = shared.item_ready.notified();
+            tokio::pin!(item_ready);
+            item_ready.as_mut().enable();
+
+            match Self::try_recv_many(&shared, buffer, limit) {
+                Ok(received) => return received,
+                Err(()) => item_ready.await,
+            }
+        }
+    }
+
+    /// Takes up to `limit` ready messages. Fails if there are none yet, but more may
+    /// still arrive.
+    fn try_recv_many(shared: &Shared<T>, buffer: &mut Vec<T>, limit: usize) -> Result<usize, ()> {
+        let mut queue = shared.queue.lock().unwrap();
+        let received = queue.items.len().min(limit);
+        if received == 0 {
+            return if queue.closed || queue.senders == 0 {
+                Ok(0)
+            } else {
+                Err(())
+            };
+        }
+        buffer.extend(queue.items.drain(..received));
+        Ok(received)
+    }
+
+    /// Stops accepting messages. Messages already queued can still be received.
+    pub(crate) fn close(&mut self) {
+        match &mut self.lane {
+            ReceiverLane::Channel(rx) => rx.close(),
+            ReceiverLane::Queue(shared) => shared.queue.lock().unwrap().closed = true,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let ReceiverLane::Queue(shared) = &self.lane else {
+            return;
+        };
+        let mut queue = shared.queue.lock().unwrap();
+        queue.closed = true;
+        let abandoned = std::mem::take(&mut queue.items);
+        drop(queue);
+        drop(abandoned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(capacity: usize, overflow: OverflowPolicy) -> MailboxConfig {
+        MailboxConfig {
+            capacity,
+            overflow,
+            ..MailboxConfig::default()
+        }
+    }
+
+    /// Negative numbers stand in for messages that may not be discarded.
+    impl Discard for i32 {
+        fn may_discard(&self) -> bool {
+            *self >= 0
+        }
+    }
+
+    impl<T> Receiver<T> {
+        async fn recv(&mut self) -> Option<T> {
+            let mut buffer = Vec::with_capacity(1);
+            self.recv_many(&mut buffer, 1).await;
+            buffer.pop()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_arrive_in_order() {
+        let (tx, mut rx) = channel(MailboxConfig::default());
+        for i in 0..3 {
+            tx.send(i).await.unwrap();
+        }
+        assert_eq!(tx.len(), 3);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_recv_ends_when_senders_are_dropped() {
+        for overflow in [OverflowPolicy::Wait, OverflowPolicy::DropOldest] {
+            let (tx, mut rx) = channel(config(2, overflow));
+            let tx2 = tx.clone();
+            tx.send(1).await.unwrap();
+            drop(tx);
+            tx2.send(2).await.unwrap();
+            drop(tx2);
+            assert_eq!(rx.recv().await, Some(1));
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_policy_waits_for_room() {
+        let (tx, mut rx) = channel(config(1, OverflowPolicy::Wait));
+        tx.send(1).await.unwrap();
+
+        let full = tokio::time::timeout(Duration::from_millis(10), tx.send(2)).await;
+        assert!(full.is_err(), "send should wait while the mailbox is full");
+
+        let sender = tokio::spawn(async move { tx.send(3).await });
+        assert_eq!(rx.recv().await, Some(1));
+        sender.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_reject_policy_refuses_when_full() {
+        let (tx, mut rx) = channel(config(2, OverflowPolicy::Reject));
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert!(matches!(tx.send(3).await, Err(SendError::Full(3))));
+        assert_eq!(tx.rejected(), 1);
+
+        assert_eq!(rx.recv().await, Some(1));
+        tx.send(4).await.unwrap();
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_policy_makes_room() {
+        let (tx, mut rx) = channel(config(2, OverflowPolicy::DropOldest));
+        for i in 1..=4 {
+            tx.send(i).await.unwrap();
+        }
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_policy_keeps_what_it_may_not_discard() {
+        let (tx, mut rx) = channel(config(2, OverflowPolicy::DropOldest));
+        tx.send(-1).await.unwrap();
+        tx.send(1).await.unwrap();
+        tx.send(-2).await.unwrap();
+        assert_eq!(tx.dropped(), 1);
+        assert!(matches!(tx.send(2).await, Err(SendError::Full(2))));
+        assert_eq!(tx.rejected(), 1);
+
+        assert_eq!(rx.recv().await, Some(-1));
+        assert_eq!(rx.recv().await, Some(-2));
+    }
+
+    #[tokio::test]
+    async fn test_close_refuses_new_messages_but_drains_old_ones() {
+        for overflow in [OverflowPolicy::Wait, OverflowPolicy::DropOldest] {
+            let (tx, mut rx) = channel(config(2, overflow));
+            tx.send(1).await.unwrap();
+            rx.close();
+            assert!(tx.is_closed());
+            assert!(matches!(tx.send(2).await, Err(SendError::Closed(2))));
+            assert_eq!(rx.recv().await, Some(1));
+            assert_eq!(rx.recv().await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropping_receiver_wakes_waiting_senders() {
+        let (tx, rx) = channel(config(1, OverflowPolicy::Wait));
+        tx.send(1).await.unwrap();
+        let sender = tokio::spawn(async move { tx.send(2).await });
+        tokio::task::yield_now().await;
+        drop(rx);
+        assert!(matches!(sender.await.unwrap(), Err(SendError::Closed(2))));
+    }
+
+    #[tokio::test]
+    async fn test_recv_many_takes_what_is_ready() {
+        let (tx, mut rx) = channel(MailboxConfig::default());
+        for i in 0..5 {
+            tx.send(i).await.unwrap();
+        }
+        let mut buffer = Vec::new();
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 3);
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 2);
+        assert_eq!(buffer, vec![0, 1, 2, 3, 4]);
+
+        drop(tx);
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 0);
+    }
+}
@@ -0,0 +1,371 @@
+//! Optional on-disk persistence for actor state.
+//!
+//! A [`Journal`] keeps two files in a directory:
+//! * `journal.log` - an append-only log of the messages that changed the state, one JSON
+//!   object per line, written before each message is applied.
+//! * `snapshot.json` - a copy of the whole state, written every so often so the log
+//!   doesn't grow forever.
+//!
+//! Every log entry carries a sequence number, and the snapshot records the last one it
+//! includes. On startup the snapshot is loaded and only the newer log entries are
+//! replayed, so a crash between writing a snapshot and trimming the log is harmless.
+//!
+//! The journal uses blocking file I/O from inside the actor. That keeps the actor's
+//! message handling synchronous, at the cost of briefly blocking a runtime thread. By
+//! default the log is only synced every [`DEFAULT_FSYNC_EVERY`] entries, so most writes
+//! just reach the operating system's cache and don't wait for the disk.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+const LOG_FILE: &str = "journal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const SNAPSHOT_TEMP_FILE: &str = "snapshot.json.tmp";
+
+/// How many entries [`PersistenceConfig::new`] lets the log collect between syncs.
+pub const DEFAULT_FSYNC_EVERY: u64 = 100;
+
+/// When the log is flushed to stable storage with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every entry. Nothing acknowledged is lost, but every write waits for the disk.
+    Always,
+    /// After every `n` entries. Up to `n - 1` entries may be lost in a power failure.
+    EveryN(u64),
+    /// Leave it to the operating system. Survives the process crashing, but not the machine.
+    Never,
+}
+
+/// Where and how an actor's state is persisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistenceConfig {
+    /// The directory holding the log and snapshot. It is created if it doesn't exist.
+    pub dir: PathBuf,
+    /// Write a snapshot and trim the log after this many logged messages.
+    pub snapshot_every: u64,
+    /// When to `fsync` the log.
+    pub fsync: FsyncPolicy,
+}
+
+impl PersistenceConfig {
+    /// Persists to `dir`, snapshotting every 1,000 messages and syncing every
+    /// [`DEFAULT_FSYNC_EVERY`] writes.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            snapshot_every: 1_000,
+            fsync: FsyncPolicy::EveryN(DEFAULT_FSYNC_EVERY),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogEntry<M> {
+    seq: u64,
+    message: M,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    seq: u64,
+    state: S,
+}
+
+/// What was found on disk when a journal was opened.
+pub struct Recovered<S, M> {
+    /// The journal, ready to append new messages.
+    pub journal: Journal<S, M>,
+    /// The state from the latest snapshot, if one has been written.
+    pub snapshot: Option<S>,
+    /// Messages logged after the snapshot, to be replayed in order.
+    pub messages: Vec<M>,
+}
+
+/// A write-ahead log of messages, plus periodic snapshots of the state they build.
+pub struct Journal<S, M> {
+    config: PersistenceConfig,
+    log: File,
+    /// The length of the log's complete entries.
+    len: u64,
+    /// The sequence number of the most recently logged message.
+    seq: u64,
+    since_snapshot: u64,
+    unsynced: u64,
+    _types: PhantomData<fn(S, M)>,
+}
+
+impl<S, M> std::fmt::Debug for Journal<S, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Journal")
+            .field("config", &self.config)
+            .field("seq", &self.seq)
+            .finish()
+    }
+}
+
+impl<S, M> Journal<S, M>
+where
+    S: Serialize + DeserializeOwned,
+    M: Serialize + DeserializeOwned,
+{
+    /// Opens the journal in `config.dir`, reading back any existing snapshot and log.
+    ///
+    /// A partially written final log line, left behind by a crash, is discarded.
+    ///
+    /// # Errors
+    /// Returns an error if the files can't be read or written, or hold invalid data.
+    pub fn open(config: PersistenceConfig) -> io::Result<Recovered<S, M>> {
+        std::fs::create_dir_all(&config.dir)?;
+
+        let (snapshot_seq, snapshot) = match std::fs::read(config.dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => {
+                let snapshot: Snapshot<S> = serde_json::from_slice(&bytes)?;
+                (snapshot.seq, Some(snapshot.state))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e),
+        };
+
+        let log_path = config.dir.join(LOG_FILE);
+        let mut seq = snapshot_seq;
+        let mut messages = Vec::new();
+        let mut valid_len = 0;
+        if let Ok(file) = File::open(&log_path) {
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                let Ok(entry) = serde_json::from_str::<LogEntry<M>>(&line) else {
+                    if line.ends_with('\n') {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("corrupt entry in {}", log_path.display()),
+                        ));
+                    }
+                    break; // A torn write at the end of the log.
+                };
+                valid_len += line.len() as u64;
+                if entry.seq > snapshot_seq {
+                    seq = entry.seq;
+                    messages.push(entry.message);
+                }
+            }
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        log.set_len(valid_len)?;
+
+        let journal = Journal {
+            since_snapshot: messages.len() as u64,
+            config,
+            log,
+            len: valid_len,
+            seq,
+            unsynced: 0,
+            _types: PhantomData,
+        };
+        Ok(Recovered {
+            journal,
+            snapshot,
+            messages,
+        })
+    }
+
+    /// Appends a message to the log, syncing it according to the fsync policy.
+    ///
+    /// # Errors
+    /// Returns an error if the entry could not be written. Whatever part of it was
+    /// written is taken back off the log, so the message is not replayed.
+    pub fn append(&mut self, message: &M) -> io::Result<()> {
+        let entry = LogEntry {
+            seq: self.seq + 1,
+            message,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => self.unsynced + 1 >= n,
+            FsyncPolicy::Never => false,
+        };
+        let written = self.log.write_all(&line).and_then(|()| {
+            if sync {
+                self.log.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            // A torn entry followed by later ones would make the whole log unreadable.
+            let _ = self.log.set_len(self.len);
+            return Err(e);
+        }
+
+        self.len += line.len() as u64;
+        self.seq += 1;
+        self.since_snapshot += 1;
+        self.unsynced = if sync { 0 } else { self.unsynced + 1 };
+        Ok(())
+    }
+
+    /// Returns `true` once enough messages have been logged to warrant a new snapshot.
+    pub fn snapshot_due(&self) -> bool {
+        self.since_snapshot >= self.config.snapshot_every
+    }
+
+    /// Writes a snapshot of `state`, which must include every logged message, then
+    /// empties the log.
+    ///
+    /// # Errors
+    /// Returns an error if the snapshot could not be written.
+    pub fn snapshot(&mut self, state: &S) -> io::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            state,
+        };
+        let temp_path = self.config.dir.join(SNAPSHOT_TEMP_FILE);
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&serde_json::to_vec(&snapshot)?)?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, self.config.dir.join(SNAPSHOT_FILE))?;
+
+        self.log.set_len(0)?;
+        self.len = 0;
+        self.since_snapshot = 0;
+        self.sync()
+    }
+
+    /// Flushes everything logged so far to stable storage.
+    ///
+    /// # Errors
+    /// Returns an error if the log could not be synced.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestJournal = Journal<Vec<String>, String>;
+
+    fn config(dir: &tempfile::TempDir) -> PersistenceConfig {
+        PersistenceConfig {
+            snapshot_every: 3,
+            ..PersistenceConfig::new(dir.path())
+        }
+    }
+
+    #[test]
+    fn test_empty_directory_recovers_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert!(recovered.snapshot.is_none());
+        assert!(recovered.messages.is_empty());
+    }
+
+    #[test]
+    fn test_log_is_replayed_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        journal.append(&"a".to_string()).unwrap();
+        journal.append(&"b".to_string()).unwrap();
+        drop(journal);
+
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert!(recovered.snapshot.is_none());
+        assert_eq!(recovered.messages, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_snapshot_replaces_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        let mut state = Vec::new();
+        for message in ["a", "b", "c", "d"] {
+            journal.append(&message.to_string()).unwrap();
+            state.push(message.to_string());
+            if journal.snapshot_due() {
+                journal.snapshot(&state).unwrap();
+            }
+        }
+        drop(journal);
+
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert_eq!(
+            recovered.snapshot,
+            Some(vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(recovered.messages, vec!["d"]);
+    }
+
+    #[test]
+    fn test_entries_covered_by_snapshot_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        journal.append(&"a".to_string()).unwrap();
+        journal.append(&"b".to_string()).unwrap();
+
+        // Simulate a crash after the snapshot was written, but before the log was trimmed.
+        let log = std::fs::read(dir.path().join(LOG_FILE)).unwrap();
+        journal.snapshot(&vec!["a".into(), "b".into()]).unwrap();
+        std::fs::write(dir.path().join(LOG_FILE), log).unwrap();
+        drop(journal);
+
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert_eq!(recovered.snapshot, Some(vec!["a".into(), "b".into()]));
+        assert!(recovered.messages.is_empty());
+    }
+
+    #[test]
+    fn test_torn_final_entry_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        journal.append(&"a".to_string()).unwrap();
+        drop(journal);
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        log.write_all(b"{\"seq\":2,\"mess").unwrap();
+        drop(log);
+
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        journal.append(&"b".to_string()).unwrap();
+        drop(journal);
+
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert_eq!(recovered.messages, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_failed_append_is_not_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = TestJournal::open(config(&dir)).unwrap().journal;
+        journal.append(&"a".to_string()).unwrap();
+
+        let log = std::mem::replace(
+            &mut journal.log,
+            OpenOptions::new().append(true).open("/dev/full").unwrap(),
+        );
+        assert!(journal.append(&"lost".to_string()).is_err());
+        journal.log = log;
+        journal.append(&"b".to_string()).unwrap();
+        drop(journal);
+
+        let recovered = TestJournal::open(config(&dir)).unwrap();
+        assert_eq!(recovered.messages, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Per-client rate limiting with token buckets, kept by an actor.
+//!
+//! Every client gets a bucket holding up to `burst` tokens, refilled at `rate` tokens per
+//! second. Each request takes a token, and is refused while the bucket is empty. Buckets
+//! are created full the first time a client is seen, and forgotten once they have been
+//! full for a while, so idle clients cost nothing.
+//!
+//! With the `tower` feature, `RateLimitLayer` puts a [`RateLimiter`] in front of any
+//! tower service, such as an axum router or a tonic server.
+
+use crate::actor::{self, Actor, ActorError, ActorMetrics, ActorRef, ActorStatus};
+use crate::registry::Registrable;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[cfg(feature = "tower")]
+pub use layer::{LimiterErrorPolicy, RateLimitLayer, RateLimitService};
+
+/// How often to look for buckets that have refilled and can be forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How fast a client may make requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Tokens added to each bucket per second: the sustained request rate.
+    pub rate: f64,
+    /// The most tokens a bucket holds: how many requests a client can make at once
+    /// after being idle.
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    /// Allows `rate` requests per second on average, in bursts of up to `burst`.
+    pub fn per_second(rate: f64, burst: u32) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// Whether a request may go ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    /// The tokens were taken, and this many are left in the bucket.
+    Allowed { remaining: u32 },
+    /// The bucket doesn't hold enough tokens. It will after `retry_after`, unless the
+    /// request asked for more than the burst, in which case it never will.
+    Limited { retry_after: Duration },
+}
+
+impl RateDecision {
+    /// Returns `true` if the request may go ahead.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, RateDecision::Allowed { .. })
+    }
+}
+
+/// Commands that can be sent to a token bucket actor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitCommand {
+    /// Takes `tokens` from `client`'s bucket if it holds enough.
+    Acquire { client: String, tokens: u32 },
+}
+
+/// A client's bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// An actor keeping a token bucket for each client.
+#[derive(Debug)]
+pub struct TokenBucket {
+    config: RateLimitConfig,
+    last_prune: Instant,
+}
+
+impl TokenBucket {
+    /// The tokens `bucket` holds at `now`, counting what has been added since it was
+    /// last updated.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let added = now.duration_since(bucket.updated).as_secs_f64() * self.config.rate;
+        (bucket.tokens + added).min(self.config.burst as f64)
+    }
+
+    /// Forgets buckets that have refilled, which are no different from new ones.
+    fn prune(&mut self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        if now.duration_since(self.last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+        let burst = self.config.burst as f64;
+        buckets.retain(|_, bucket| self.refilled(bucket, now) < burst);
+    }
+}
+
+impl Actor for TokenBucket {
+    type State = HashMap<String, Bucket>;
+    type Message = RateLimitCommand;
+    type Reply = RateDecision;
+
+    fn handle(
+        &mut self,
+        buckets: &mut HashMap<String, Bucket>,
+        command: RateLimitCommand,
+    ) -> RateDecision {
+        let RateLimitCommand::Acquire { client, tokens } = command;
+        let now = Instant::now();
+        self.prune(buckets, now);
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.config.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        let wanted = tokens as f64;
+        if bucket.tokens >= wanted {
+            bucket.tokens -= wanted;
+            RateDecision::Allowed {
+                remaining: bucket.tokens as u32,
+            }
+        } else if tokens > self.config.burst {
+            RateDecision::Limited {
+                retry_after: Duration::MAX,
+            }
+        } else {
+            let missing = wanted - bucket.tokens;
+            RateDecision::Limited {
+                retry_after: Duration::from_secs_f64(missing / self.config.rate),
+            }
+        }
+    }
+
+    fn message_name(_command: &RateLimitCommand) -> &'static str {
+        "acquire"
+    }
+}
+
+/// A cloneable handle to a token bucket actor.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    actor: ActorRef<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Returns a copy of this handle that gives up on every call after `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            actor: self.actor.with_timeout(timeout),
+        }
+    }
+
+    /// Takes one token for a request from `client`.
+    pub async fn check(&self, client: &str) -> Result<RateDecision, ActorError> {
+        self.acquire(client, 1).await
+    }
+
+    /// Takes `tokens` from `client`'s bucket, for requests that cost more than one.
+    ///
+    /// Nothing is taken if the bucket doesn't hold enough.
+    pub async fn acquire(&self, client: &str, tokens: u32) -> Result<RateDecision, ActorError> {
+        self.actor
+            .ask(RateLimitCommand::Acquire {
+                client: client.to_string(),
+                tokens,
+            })
+            .await
+    }
+
+    /// Stops the actor once it has answered the checks already sent to it.
+    pub async fn shutdown(&self) {
+        self.actor.shutdown().await;
+    }
+}
+
+impl Registrable for RateLimiter {
+    fn status(&self) -> ActorStatus {
+        self.actor.status()
+    }
+
+    fn metrics(&self) -> ActorMetrics {
+        self.actor.metrics()
+    }
+}
+
+/// Starts a rate limiter.
+///
+/// # Arguments
+/// * `config` - The refill rate and burst size of every client's bucket
+///
+/// # Panics
+/// Panics if the rate isn't a positive number or the burst is 0.
+pub fn start_rate_limiter(config: RateLimitConfig) -> RateLimiter {
+    assert!(
+        config.rate > 0.0 && config.rate.is_finite(),
+        "the refill rate must be a positive number"
+    );
+    assert!(config.burst > 0, "the burst must be at least 1");
+    let actor = TokenBucket {
+        config,
+        last_prune: Instant::now(),
+    };
+    RateLimiter {
+        actor: actor::spawn(actor, HashMap::new()),
+    }
+}
+
+#[cfg(feature = "tower")]
+mod layer {
+    use super::{RateDecision, RateLimiter};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    /// How long a client refused because the rate limiter isn't answering is told to wait.
+    const LIMITER_ERROR_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+    /// What [`RateLimitLayer`] does with a request when the rate limiter isn't answering,
+    /// because it has stopped or timed out.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LimiterErrorPolicy {
+        /// Refuses the request as if the client were limited, asking it to retry after a
+        /// second. Nothing gets through unlimited, but the service is down while the
+        /// rate limiter is.
+        Reject,
+        /// Lets the request through without a limit, so the service stays up without
+        /// the rate limiter.
+        Allow,
+    }
+
+    /// A tower layer that refuses requests from clients over their rate limit.
+    ///
+    /// It works with any request type: `key` picks the client a request comes from, and
+    /// `reject` builds the response sent instead when the client is limited, given how
+    /// long it should wait. What happens when the rate limiter itself isn't answering is
+    /// up to the [`LimiterErrorPolicy`].
+    pub struct RateLimitLayer<K, R> {
+        limiter: RateLimiter,
+        key: Arc<K>,
+        reject: Arc<R>,
+        on_error: LimiterErrorPolicy,
+    }
+
+    impl<K, R> RateLimitLayer<K, R> {
+        /// Limits requests with `limiter`.
+        ///
+        /// # Arguments
+        /// * `limiter` - The rate limiter to check every request with
+        /// * `key` - Picks the client id of a request. Clients can get around the limit
+        ///   by changing anything this is taken from, so it should come from something
+        ///   they can't choose, such as the address they connect from
+        /// * `reject` - Builds the response for a limited request, from how long the
+        ///   client should wait before retrying
+        /// * `on_error` - Whether requests are refused or let through while the rate
+        ///   limiter isn't answering
+        pub fn new(limiter: RateLimiter, key: K, reject: R, on_error: LimiterErrorPolicy) -> Self {
+            Self {
+                limiter,
+                key: Arc::new(key),
+                reject: Arc::new(reject),
+                on_error,
+            }
+        }
+    }
+
+    impl<K, R> Clone for RateLimitLayer<K, R> {
+        fn clone(&self) -> Self {
+            Self {
+                limiter: self.limiter.clone(),
+                key: self.key.clone(),
+                reject: self.reject.clone(),
+                on_error: self.on_error,
+            }
+        }
+    }
+
+    impl<S, K, R> Layer<S> for RateLimitLayer<K, R> {
+        type Service = RateLimitService<S, K, R>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            RateLimitService {
+                inner,
+                limiter: self.limiter.clone(),
+                key: self.key.clone(),
+                reject: self.reject.clone(),
+                on_error: self.on_error,
+            }
+        }
+    }
+
+    /// A service wrapped by [`RateLimitLayer`].
+    pub struct RateLimitService<S, K, R> {
+        inner: S,
+        limiter: RateLimiter,
+        key: Arc<K>,
+        reject: Arc<R>,
+        on_error: LimiterErrorPolicy,
+    }
+
+    impl<S: Clone, K, R> Clone for RateLimitService<S, K, R> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                limiter: self.limiter.clone(),
+                key: self.key.clone(),
+                reject: self.reject.clone(),
+                on_error: self.on_error,
+            }
+        }
+    }
+
+    impl<S, K, R, Req> Service<Req> for RateLimitService<S, K, R>
+    where
+        S: Service<Req> + Clone + Send + 'static,
+        S::Future: Send,
+        K: Fn(&Req) -> String,
+        R: Fn(Duration) -> S::Response + Send + Sync + 'static,
+        Req: Send + 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: Req) -> Self::Future {
+            let client = (self.key)(&request);
+            // The inner service that was polled ready is the one that must be called.
+            let clone = self.inner.clone();
+            let mut inner = std::mem::replace(&mut self.inner, clone);
+            let limiter = self.limiter.clone();
+            let reject = self.reject.clone();
+            let on_error = self.on_error;
+            Box::pin(async move {
+                match limiter.check(&client).await {
+                    Ok(RateDecision::Allowed { .. }) => {}
+                    Ok(RateDecision::Limited { retry_after }) => {
+                        tracing::debug!(client, ?retry_after, "rate limited");
+                        return Ok(reject(retry_after));
+                    }
+                    Err(err) => match on_error {
+                        LimiterErrorPolicy::Reject => {
+                            tracing::warn!(%err, "the rate limiter isn't answering, refusing a request");
+                            return Ok(reject(LIMITER_ERROR_RETRY_AFTER));
+                        }
+                        LimiterErrorPolicy::Allow => {
+                            tracing::warn!(%err, "the rate limiter isn't answering, letting a request through");
+                        }
+                    },
+                }
+                inner.call(request).await
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst_then_refill() {
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(2.0, 3));
+        for remaining in [2, 1, 0] {
+            assert_eq!(
+                limiter.check("a").await,
+                Ok(RateDecision::Allowed { remaining })
+            );
+        }
+        assert_eq!(
+            limiter.check("a").await,
+            Ok(RateDecision::Limited {
+                retry_after: Duration::from_millis(500)
+            })
+        );
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(limiter.check("a").await.unwrap().is_allowed());
+        assert!(!limiter.check("a").await.unwrap().is_allowed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clients_have_separate_buckets() {
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(1.0, 1));
+        assert!(limiter.check("a").await.unwrap().is_allowed());
+        assert!(!limiter.check("a").await.unwrap().is_allowed());
+        assert!(limiter.check("b").await.unwrap().is_allowed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buckets_never_hold_more_than_the_burst() {
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(10.0, 2));
+        limiter.check("a").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(
+            limiter.acquire("a", 2).await,
+            Ok(RateDecision::Allowed { remaining: 0 })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_over_the_burst_never_pass() {
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(1.0, 2));
+        assert_eq!(
+            limiter.acquire("a", 3).await,
+            Ok(RateDecision::Limited {
+                retry_after: Duration::MAX
+            })
+        );
+        // Nothing was taken.
+        assert_eq!(
+            limiter.acquire("a", 2).await,
+            Ok(RateDecision::Allowed { remaining: 0 })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refilled_buckets_are_forgotten() {
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(1.0, 1));
+        limiter.check("idle").await.unwrap();
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+        limiter.check("busy").await.unwrap();
+
+        let buckets = limiter.actor.inspect().await.unwrap().state;
+        assert!(buckets.contains_key("busy"));
+        assert!(!buckets.contains_key("idle"));
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test(start_paused = true)]
+    async fn test_layer_rejects_limited_clients() {
+        use std::convert::Infallible;
+        use std::future::Ready;
+        use std::task::{Context, Poll};
+        use tower_layer::Layer;
+        use tower_service::Service;
+
+        /// Answers every request with "ok".
+        #[derive(Clone)]
+        struct Answer;
+
+        impl Service<&'static str> for Answer {
+            type Response = String;
+            type Error = Infallible;
+            type Future = Ready<Result<String, Infallible>>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _client: &'static str) -> Self::Future {
+                std::future::ready(Ok("ok".to_string()))
+            }
+        }
+
+        let limiter = start_rate_limiter(RateLimitConfig::per_second(1.0, 1));
+        let layer = |on_error| {
+            RateLimitLayer::new(
+                limiter.clone(),
+                |client: &&'static str| client.to_string(),
+                |retry_after: Duration| format!("retry in {}ms", retry_after.as_millis()),
+                on_error,
+            )
+        };
+        let mut service = layer(LimiterErrorPolicy::Reject).layer(Answer);
+
+        assert_eq!(service.call("a").await, Ok("ok".to_string()));
+        assert_eq!(service.call("a").await, Ok("retry in 1000ms".to_string()));
+        assert_eq!(service.call("b").await, Ok("ok".to_string()));
+
+        // Once the rate limiter has stopped, the policy decides.
+        limiter.shutdown().await;
+        assert_eq!(service.call("c").await, Ok("retry in 1000ms".to_string()));
+        let mut service = layer(LimiterErrorPolicy::Allow).layer(Answer);
+        assert_eq!(service.call("c").await, Ok("ok".to_string()));
+    }
+}
@@ -0,0 +1,364 @@
+//! A directory of actors, so code anywhere in the process can find them.
+//!
+//! Handles are registered under a name, or under their own type when there is only one
+//! of a kind, and looked up again from handlers, background jobs or services without
+//! being passed around. The registry holds a clone of each handle, which keeps the actor
+//! alive until it is unregistered or shut down.
+
+use crate::actor::{Actor, ActorMetrics, ActorRef, ActorStatus};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A handle that can be kept in a [`Registry`].
+pub trait Registrable: Any + Clone + Send + Sync {
+    /// Where the actor behind the handle is in its lifecycle.
+    fn status(&self) -> ActorStatus;
+
+    /// The metrics of the actor behind the handle.
+    fn metrics(&self) -> ActorMetrics;
+}
+
+impl<A: Actor> Registrable for ActorRef<A> {
+    fn status(&self) -> ActorStatus {
+        ActorRef::status(self)
+    }
+
+    fn metrics(&self) -> ActorMetrics {
+        ActorRef::metrics(self)
+    }
+}
+
+/// Errors from registering an actor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    /// Another actor that hasn't stopped is already registered under the key, which is
+    /// the name or the type name.
+    AlreadyRegistered(String),
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::AlreadyRegistered(key) => {
+                write!(f, "an actor is already registered as {key}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// What a registered actor is looked up by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Name(String),
+    Type(TypeId),
+}
+
+/// The parts of a [`Registrable`] handle the registry needs once its type is erased.
+trait Entry: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn status(&self) -> ActorStatus;
+    fn metrics(&self) -> ActorMetrics;
+}
+
+impl<H: Registrable> Entry for H {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn status(&self) -> ActorStatus {
+        Registrable::status(self)
+    }
+
+    fn metrics(&self) -> ActorMetrics {
+        Registrable::metrics(self)
+    }
+}
+
+struct Registered {
+    /// The name, or the type name for actors registered by type.
+    key: String,
+    type_name: &'static str,
+    registered_at: Instant,
+    entry: Box<dyn Entry>,
+}
+
+/// A registered actor, as listed by [`Registry::list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActorInfo {
+    /// The name the actor is registered under, or its type name if it was registered
+    /// by type.
+    pub key: String,
+    /// The type of the registered handle.
+    pub type_name: &'static str,
+    /// Where the actor is in its lifecycle.
+    pub status: ActorStatus,
+    /// How long ago the actor was registered.
+    pub registered_for: Duration,
+    /// The actor's metrics.
+    pub metrics: ActorMetrics,
+}
+
+/// A directory of actors, looked up by name or by type.
+///
+/// Cloning a `Registry` gives another handle to the same directory. Most programs only
+/// need the one returned by [`Registry::global`].
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<RwLock<HashMap<Key, Registered>>>,
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("actors", &self.entries.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registry shared by the whole process.
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    /// Registers `handle` under `name`.
+    ///
+    /// An actor that has stopped is replaced, so a restarted service can register again
+    /// under its old name.
+    ///
+    /// # Errors
+    /// Returns [`RegistryError::AlreadyRegistered`] if an actor that hasn't stopped is
+    /// already registered under `name`.
+    pub fn register<H: Registrable>(
+        &self,
+        name: impl Into<String>,
+        handle: H,
+    ) -> Result<(), RegistryError> {
+        let name = name.into();
+        self.insert(Key::Name(name.clone()), name, handle)
+    }
+
+    /// Registers `handle` under its own type, for actors there is only one of.
+    ///
+    /// # Errors
+    /// Returns [`RegistryError::AlreadyRegistered`] if an actor of the same type that
+    /// hasn't stopped is already registered by type.
+    pub fn register_type<H: Registrable>(&self, handle: H) -> Result<(), RegistryError> {
+        let key = std::any::type_name::<H>().to_string();
+        self.insert(Key::Type(TypeId::of::<H>()), key, handle)
+    }
+
+    fn insert<H: Registrable>(
+        &self,
+        key: Key,
+        name: String,
+        handle: H,
+    ) -> Result<(), RegistryError> {
+        let mut entries = self.entries.write().unwrap();
+        if let Some(existing) = entries.get(&key)
+            && existing.entry.status() != ActorStatus::Stopped
+        {
+            return Err(RegistryError::AlreadyRegistered(name));
+        }
+        entries.insert(
+            key,
+            Registered {
+                key: name,
+                type_name: std::any::type_name::<H>(),
+                registered_at: Instant::now(),
+                entry: Box::new(handle),
+            },
+        );
+        Ok(())
+    }
+
+    /// Looks up the actor registered under `name`.
+    ///
+    /// # Returns
+    /// A clone of the handle, or `None` if nothing is registered under `name` or it was
+    /// registered as a different type of handle.
+    pub fn lookup<H: Registrable>(&self, name: &str) -> Option<H> {
+        self.get(&Key::Name(name.to_string()))
+    }
+
+    /// Looks up the actor registered under the type `H`.
+    pub fn lookup_type<H: Registrable>(&self) -> Option<H> {
+        self.get(&Key::Type(TypeId::of::<H>()))
+    }
+
+    fn get<H: Registrable>(&self, key: &Key) -> Option<H> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(key)?
+            .entry
+            .as_any()
+            .downcast_ref::<H>()
+            .cloned()
+    }
+
+    /// Removes the actor registered under `name`, which stops it once no other handles
+    /// to it are left.
+    ///
+    /// # Returns
+    /// `true` if an actor was registered under `name`.
+    pub fn unregister(&self, name: &str) -> bool {
+        self.remove(&Key::Name(name.to_string()))
+    }
+
+    /// Removes the actor registered under the type `H`.
+    pub fn unregister_type<H: Registrable>(&self) -> bool {
+        self.remove(&Key::Type(TypeId::of::<H>()))
+    }
+
+    fn remove(&self, key: &Key) -> bool {
+        // Dropped outside the lock, in case it was the last handle to its actor.
+        let removed = self.entries.write().unwrap().remove(key);
+        removed.is_some()
+    }
+
+    /// Lists every registered actor, whatever its status, sorted by key.
+    pub fn list(&self) -> Vec<ActorInfo> {
+        let entries = self.entries.read().unwrap();
+        let mut actors: Vec<ActorInfo> = entries
+            .values()
+            .map(|registered| ActorInfo {
+                key: registered.key.clone(),
+                type_name: registered.type_name,
+                status: registered.entry.status(),
+                registered_for: registered.registered_at.elapsed(),
+                metrics: registered.entry.metrics(),
+            })
+            .collect();
+        actors.sort_by(|a, b| a.key.cmp(&b.key));
+        actors
+    }
+
+    /// Lists the registered actors that are still running, sorted by key.
+    pub fn running(&self) -> Vec<ActorInfo> {
+        let mut actors = self.list();
+        actors.retain(|actor| actor.status == ActorStatus::Running);
+        actors
+    }
+
+    /// Removes every actor that has stopped.
+    ///
+    /// # Returns
+    /// The number of actors removed.
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.write().unwrap();
+        let before = entries.len();
+        entries.retain(|_, registered| registered.entry.status() != ActorStatus::Stopped);
+        before - entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor;
+
+    struct Echo;
+
+    impl Actor for Echo {
+        type State = ();
+        type Message = u32;
+        type Reply = u32;
+
+        fn handle(&mut self, _state: &mut (), message: u32) -> u32 {
+            message
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_by_name() {
+        let registry = Registry::new();
+        registry.register("echo", actor::spawn(Echo, ())).unwrap();
+
+        let echo: ActorRef<Echo> = registry.lookup("echo").unwrap();
+        assert_eq!(echo.ask(7).await, Ok(7));
+        assert!(registry.lookup::<ActorRef<Echo>>("missing").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_with_the_wrong_type_finds_nothing() {
+        let registry = Registry::new();
+        registry.register("echo", actor::spawn(Echo, ())).unwrap();
+        assert!(registry.lookup::<crate::ActorHandle>("echo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_by_type() {
+        let registry = Registry::new();
+        registry.register_type(actor::spawn(Echo, ())).unwrap();
+
+        let echo = registry.lookup_type::<ActorRef<Echo>>().unwrap();
+        assert_eq!(echo.ask(3).await, Ok(3));
+        assert_eq!(
+            registry.register_type(actor::spawn(Echo, ())),
+            Err(RegistryError::AlreadyRegistered(
+                std::any::type_name::<ActorRef<Echo>>().to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_names_are_unique_while_running() {
+        let registry = Registry::new();
+        let first = actor::spawn(Echo, ());
+        registry.register("echo", first.clone()).unwrap();
+        assert_eq!(
+            registry.register("echo", actor::spawn(Echo, ())),
+            Err(RegistryError::AlreadyRegistered("echo".to_string()))
+        );
+
+        // Once the first one has stopped, the name can be taken again.
+        first.shutdown().await;
+        registry.register("echo", actor::spawn(Echo, ())).unwrap();
+        assert_eq!(registry.running().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_shows_lifecycle() {
+        let registry = Registry::new();
+        let stopped = actor::spawn(Echo, ());
+        registry.register("b", stopped.clone()).unwrap();
+        registry.register("a", actor::spawn(Echo, ())).unwrap();
+        stopped.ask(1).await.unwrap();
+        stopped.shutdown().await;
+
+        let actors = registry.list();
+        let keys: Vec<_> = actors.iter().map(|actor| actor.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(actors[0].status, ActorStatus::Running);
+        assert_eq!(actors[1].status, ActorStatus::Stopped);
+        assert_eq!(actors[1].metrics.processed, 1);
+        assert_eq!(actors[1].type_name, std::any::type_name::<ActorRef<Echo>>());
+
+        assert_eq!(registry.running().len(), 1);
+        assert_eq!(registry.prune(), 1);
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unregister_releases_the_actor() {
+        let registry = Registry::new();
+        let echo = actor::spawn(Echo, ());
+        let stopped = crate::testing::when_stopped(&echo);
+        registry.register("echo", echo).unwrap();
+
+        assert!(registry.unregister("echo"));
+        assert!(!registry.unregister("echo"));
+        stopped.await;
+    }
+}
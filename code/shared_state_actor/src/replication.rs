@@ -0,0 +1,881 @@
+//! A counter replicated across processes over TCP.
+//!
+//! Each process runs a replica that keeps a [`GCounter`]: a count of the increments made
+//! on every node, keyed by node id. A node only ever increments its own entry, and two
+//! copies are merged by taking the larger count for each node, so merging is idempotent
+//! and order doesn't matter. Replicas send their whole state to their peers whenever it
+//! changes, and again every `gossip_interval` so peers that were down catch up. Once
+//! increments stop, every replica converges to the same global total.
+//!
+//! Only increments can be replicated this way; the counter never goes down.
+//!
+//! Nothing is persisted. Each time a node starts it begins a new run, which replaces its
+//! entry from the last run, folding that run's count into the entry's total. A restarted
+//! node starts counting from 0 without its peers' count for the last run hiding its new
+//! increments, and its entry doesn't grow with every restart.
+//!
+//! On the wire, each state is one JSON object per line.
+//!
+//! # Trust
+//!
+//! Peers aren't authenticated, and anything that can connect can send a state. A state
+//! can only hold [`MAX_NODES`] nodes, and a connection from an address that isn't one of
+//! the replica's peers can't add nodes or move a node's total by more than
+//! [`MAX_UNKNOWN_PEER_JUMP`]. A peer's state is still taken at its word, and the counter
+//! never goes down, so a bad count from a peer is there for good: only listen on a
+//! network where everything that can reach the replica is trusted.
+
+use crate::actor::{self, Actor, ActorError, ActorMetrics, ActorRef, ActorStatus};
+use crate::registry::Registrable;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+/// How long to wait for a peer to accept a connection before trying again later.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a peer gets to take a state before its connection is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest state a peer may send, in bytes. A peer that sends a longer line is
+/// disconnected, rather than buffered without limit while it never ends the line.
+///
+/// [`MAX_NODES`] nodes with ids of [`MAX_NODE_ID_LENGTH`] fit in well under this.
+const MAX_STATE_LENGTH: usize = 1024 * 1024;
+
+/// The most nodes a [`GCounter`] takes in. Entries for more nodes are rejected.
+pub const MAX_NODES: usize = 1000;
+
+/// The longest node id a [`GCounter`] takes in, in bytes.
+pub const MAX_NODE_ID_LENGTH: usize = 64;
+
+/// The most a state from an address that isn't one of the replica's peers may add to a
+/// node's total. Larger jumps are rejected.
+pub const MAX_UNKNOWN_PEER_JUMP: u64 = 1_000_000;
+
+/// One node's entry in a [`GCounter`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeCount {
+    /// When the node's current run started, in nanoseconds since the Unix epoch. A later
+    /// run replaces an earlier one.
+    pub run: u64,
+    /// The total of the node's earlier runs, as far as is known.
+    pub earlier: u64,
+    /// The increments made during the current run.
+    pub count: u64,
+}
+
+impl NodeCount {
+    /// The node's total across all its runs, saturating at `u64::MAX`.
+    pub fn total(&self) -> u64 {
+        self.earlier.saturating_add(self.count)
+    }
+
+    /// Combines two copies of a node's entry.
+    ///
+    /// Copies of the same run take the larger of each count. Otherwise the later run
+    /// wins, and the earlier run's total is folded into what it knows of earlier runs.
+    fn merged(self, other: NodeCount) -> NodeCount {
+        match self.run.cmp(&other.run) {
+            Ordering::Equal => NodeCount {
+                run: self.run,
+                earlier: self.earlier.max(other.earlier),
+                count: self.count.max(other.count),
+            },
+            Ordering::Less => NodeCount {
+                earlier: other.earlier.max(self.total()),
+                ..other
+            },
+            Ordering::Greater => other.merged(self),
+        }
+    }
+}
+
+/// What a merge took in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Merged {
+    /// Whether the counter changed.
+    pub changed: bool,
+    /// How many entries were left out, for being over the limits.
+    pub rejected: usize,
+}
+
+/// A grow-only counter that can be merged with copies from other nodes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    nodes: BTreeMap<String, NodeCount>,
+}
+
+impl GCounter {
+    /// Adds `amount` to `node`'s count, saturating at `u64::MAX`.
+    pub fn add(&mut self, node: &str, amount: u64) {
+        let entry = self.nodes.entry(node.to_string()).or_default();
+        entry.count = entry.count.saturating_add(amount);
+    }
+
+    /// Starts a new run of `node`, folding its count so far into the new run's entry.
+    /// Does nothing if `run` isn't later than the node's current run.
+    pub fn start_run(&mut self, node: &str, run: u64) {
+        let entry = self.nodes.entry(node.to_string()).or_default();
+        *entry = entry.merged(NodeCount {
+            run,
+            earlier: 0,
+            count: 0,
+        });
+    }
+
+    /// Takes in everything `other` knows about, up to [`MAX_NODES`] nodes with ids of
+    /// up to [`MAX_NODE_ID_LENGTH`].
+    ///
+    /// # Returns
+    /// `true` if this counter changed.
+    pub fn merge(&mut self, other: &GCounter) -> bool {
+        self.merge_within(other, None).changed
+    }
+
+    /// Takes in what `other` knows about nodes already in this counter, as long as no
+    /// node's total goes up by more than `max_jump`.
+    pub fn merge_known_nodes(&mut self, other: &GCounter, max_jump: u64) -> Merged {
+        self.merge_within(other, Some(max_jump))
+    }
+
+    /// Merges `other`'s entries, leaving out those over the limits. With a `max_jump`,
+    /// new nodes are left out too.
+    fn merge_within(&mut self, other: &GCounter, max_jump: Option<u64>) -> Merged {
+        let mut merged = Merged::default();
+        for (node, &theirs) in &other.nodes {
+            let Some(ours) = self.nodes.get_mut(node) else {
+                let full = self.nodes.len() >= MAX_NODES;
+                if max_jump.is_some() || full || node.len() > MAX_NODE_ID_LENGTH {
+                    merged.rejected += 1;
+                } else {
+                    self.nodes.insert(node.clone(), theirs);
+                    merged.changed = true;
+                }
+                continue;
+            };
+            let combined = ours.merged(theirs);
+            let jump = combined.total().saturating_sub(ours.total());
+            if max_jump.is_some_and(|max_jump| jump > max_jump) {
+                merged.rejected += 1;
+            } else if combined != *ours {
+                *ours = combined;
+                merged.changed = true;
+            }
+        }
+        merged
+    }
+
+    /// The total across every node, saturating at `u64::MAX`.
+    pub fn value(&self) -> u64 {
+        self.nodes
+            .values()
+            .fold(0, |total, node| total.saturating_add(node.total()))
+    }
+
+    /// Each node's entry.
+    pub fn nodes(&self) -> &BTreeMap<String, NodeCount> {
+        &self.nodes
+    }
+
+    /// The total contributed by each node, across all its runs.
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        self.nodes
+            .iter()
+            .map(|(node, count)| (node.clone(), count.total()))
+            .collect()
+    }
+}
+
+/// Commands that can be sent to a replica.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaCommand {
+    /// Adds to this node's count.
+    Add(u64),
+    /// Merges in a state received from a peer.
+    Merge {
+        state: GCounter,
+        /// Whether it came from the address of one of the replica's peers. If not, only
+        /// nodes already known are updated, within [`MAX_UNKNOWN_PEER_JUMP`].
+        from_peer: bool,
+    },
+    /// Retrieves the global total as far as this replica knows it.
+    Get,
+}
+
+/// An actor holding one node's copy of a [`GCounter`]. Every command is answered with
+/// the global total after it was applied.
+#[derive(Debug)]
+pub struct Replica {
+    node_id: String,
+    run: u64,
+}
+
+impl Actor for Replica {
+    type State = GCounter;
+    type Message = ReplicaCommand;
+    type Reply = u64;
+
+    fn handle(&mut self, counter: &mut GCounter, command: ReplicaCommand) -> u64 {
+        match command {
+            ReplicaCommand::Add(amount) => counter.add(&self.node_id, amount),
+            ReplicaCommand::Merge { state, from_peer } => {
+                let merged = if from_peer {
+                    counter.merge_within(&state, None)
+                } else {
+                    counter.merge_known_nodes(&state, MAX_UNKNOWN_PEER_JUMP)
+                };
+                if merged.rejected > 0 {
+                    tracing::warn!(rejected = merged.rejected, "left out part of a state");
+                }
+                // A later run of this node than our own means the clock went back since
+                // the last run. Our count was folded into it, so carry on in a run after it.
+                let latest = counter.nodes.get(&self.node_id).map_or(0, |ours| ours.run);
+                if latest > self.run {
+                    tracing::warn!(node = self.node_id, "found a later run of this node");
+                    self.run = latest + 1;
+                    counter.start_run(&self.node_id, self.run);
+                }
+            }
+            ReplicaCommand::Get => {}
+        }
+        counter.value()
+    }
+
+    fn message_name(command: &ReplicaCommand) -> &'static str {
+        match command {
+            ReplicaCommand::Add(_) => "add",
+            ReplicaCommand::Merge { .. } => "merge",
+            ReplicaCommand::Get => "get",
+        }
+    }
+}
+
+/// How a replica is identified and finds its peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationConfig {
+    /// Names this node. Must be unique among the replicas, and at most
+    /// [`MAX_NODE_ID_LENGTH`] bytes long.
+    ///
+    /// A node that restarts under the same id picks up its total from its peers.
+    /// Increments from earlier runs that reached a peer stay in the total; any that
+    /// hadn't been sent yet are lost.
+    pub node_id: String,
+    /// The address to accept peer connections on. Port 0 picks a free port, which
+    /// [`ReplicatedHandle::local_addr`] reports. Peers aren't authenticated, so this
+    /// must only be reachable from a trusted network.
+    pub listen: SocketAddr,
+    /// The other replicas to send state to. More can be added with
+    /// [`ReplicatedHandle::add_peer`]. States from their addresses can add new nodes.
+    pub peers: Vec<SocketAddr>,
+    /// How often to resend the state to every peer even if it hasn't changed, so peers
+    /// that missed an update catch up.
+    pub gossip_interval: Duration,
+}
+
+impl ReplicationConfig {
+    /// Replicates as `node_id`, listening on `listen`, with no peers yet and resending
+    /// the state every second.
+    pub fn new(node_id: impl Into<String>, listen: SocketAddr) -> Self {
+        Self {
+            node_id: node_id.into(),
+            listen,
+            peers: Vec::new(),
+            gossip_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A cloneable handle to a replicated counter.
+///
+/// The replica keeps running, and listening for peers, until [`ReplicatedHandle::shutdown`]
+/// is called.
+#[derive(Clone, Debug)]
+pub struct ReplicatedHandle {
+    actor: ActorRef<Replica>,
+    node_id: String,
+    local_addr: SocketAddr,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl ReplicatedHandle {
+    /// Returns a copy of this handle that gives up on every call after `timeout`.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            actor: self.actor.with_timeout(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// This node's id.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The address the replica accepts peer connections on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts sending state to another replica, and taking new nodes from its address.
+    pub fn add_peer(&self, peer: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+
+    /// Increments this node's count by 1.
+    ///
+    /// # Returns
+    /// The global total as far as this replica knows it.
+    pub async fn increment(&self) -> Result<u64, ActorError> {
+        self.add(1).await
+    }
+
+    /// Adds `amount` to this node's count.
+    ///
+    /// # Returns
+    /// The global total as far as this replica knows it.
+    pub async fn add(&self, amount: u64) -> Result<u64, ActorError> {
+        self.actor.ask(ReplicaCommand::Add(amount)).await
+    }
+
+    /// Retrieves the global total as far as this replica knows it. It catches up with
+    /// the other replicas within about one gossip round.
+    pub async fn get(&self) -> Result<u64, ActorError> {
+        self.actor.ask(ReplicaCommand::Get).await
+    }
+
+    /// Retrieves this replica's copy of the counter, with each node's count.
+    pub async fn state(&self) -> Result<GCounter, ActorError> {
+        Ok(self.actor.inspect().await?.state)
+    }
+
+    /// Stops the replica, and stops listening for and sending to peers.
+    pub async fn shutdown(&self) {
+        self.actor.shutdown().await;
+    }
+}
+
+impl Registrable for ReplicatedHandle {
+    fn status(&self) -> ActorStatus {
+        self.actor.status()
+    }
+
+    fn metrics(&self) -> ActorMetrics {
+        self.actor.metrics()
+    }
+}
+
+/// Starts a replica of a counter shared with other processes.
+///
+/// # Arguments
+/// * `config` - This node's id, the address to listen on and the peers to send to
+///
+/// # Errors
+/// Returns an error if the node id is longer than [`MAX_NODE_ID_LENGTH`], or the
+/// listening address can't be bound.
+pub async fn start_replicated(config: ReplicationConfig) -> io::Result<ReplicatedHandle> {
+    if config.node_id.len() > MAX_NODE_ID_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("node ids can be at most {MAX_NODE_ID_LENGTH} bytes long"),
+        ));
+    }
+    let listener = TcpListener::bind(config.listen).await?;
+    let local_addr = listener.local_addr()?;
+    let run = run_started();
+    let mut counter = GCounter::default();
+    counter.start_run(&config.node_id, run);
+    let actor = actor::spawn(
+        Replica {
+            node_id: config.node_id.clone(),
+            run,
+        },
+        counter,
+    );
+    let changes = actor
+        .subscribe()
+        .await
+        .expect("a newly spawned replica is running");
+    let peers = Arc::new(Mutex::new(config.peers));
+
+    tokio::spawn(accept_peers(listener, actor.clone(), peers.clone()));
+    tokio::spawn(gossip(changes, peers.clone(), config.gossip_interval));
+    tracing::info!(node = config.node_id, run, %local_addr, "replica started");
+
+    Ok(ReplicatedHandle {
+        actor,
+        node_id: config.node_id,
+        local_addr,
+        peers,
+    })
+}
+
+/// Picks the run a replica starting now counts under: the time in nanoseconds since the
+/// Unix epoch, so a restarted node's run comes after its last one.
+fn run_started() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos().try_into().unwrap_or(u64::MAX))
+}
+
+/// Accepts connections from peers until the replica stops.
+async fn accept_peers(
+    listener: TcpListener,
+    actor: ActorRef<Replica>,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    tokio::spawn(receive_states(stream, peer, actor.clone(), peers.clone()));
+                }
+                Err(err) => tracing::warn!(%err, "failed to accept a peer"),
+            },
+            _ = actor.stopped() => break,
+        }
+    }
+}
+
+/// Merges every state a peer sends, until it disconnects or the replica stops.
+///
+/// States from an address that isn't one of `peers` can only update nodes already known.
+async fn receive_states(
+    stream: TcpStream,
+    peer: SocketAddr,
+    actor: ActorRef<Replica>,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+) {
+    let mut lines = FramedRead::new(stream, LinesCodec::new_with_max_length(MAX_STATE_LENGTH));
+    loop {
+        let line = tokio::select! {
+            line = lines.next() => line,
+            _ = actor.stopped() => break,
+        };
+        let line = match line {
+            Some(Ok(line)) => line,
+            None => break,
+            Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                tracing::warn!(%peer, "dropping a peer that sent an oversized state");
+                break;
+            }
+            Some(Err(LinesCodecError::Io(err))) => {
+                tracing::debug!(%peer, %err, "lost a peer");
+                break;
+            }
+        };
+        match serde_json::from_str::<GCounter>(&line) {
+            Ok(state) => {
+                // Peers connect from any port, so only the address is compared.
+                let from_peer = peers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|known| known.ip() == peer.ip());
+                let merge = ReplicaCommand::Merge { state, from_peer };
+                if actor.tell(merge).await.is_err() {
+                    break;
+                }
+            }
+            Err(err) => tracing::warn!(%peer, %err, "ignoring a malformed state"),
+        }
+    }
+}
+
+/// Sends the state to every peer whenever it changes and every `interval`, until the
+/// replica stops.
+///
+/// Each peer is sent to by a task of its own, so a peer that is slow to connect to or
+/// stops reading only holds up its own sends. While a send to a peer is still in
+/// flight, that peer is skipped; the next round sends it the latest state.
+async fn gossip(
+    mut changes: watch::Receiver<GCounter>,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    interval: Duration,
+) {
+    // Open connections not in use, and the peer each send in flight is to.
+    let mut connections: HashMap<SocketAddr, TcpStream> = HashMap::new();
+    let mut sending: HashMap<task::Id, SocketAddr> = HashMap::new();
+    let mut sends = JoinSet::new();
+    let resend = tokio::time::sleep(interval);
+    tokio::pin!(resend);
+    loop {
+        tokio::select! {
+            changed = changes.changed() => {
+                // The replica has stopped.
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = &mut resend => {}
+            Some(sent) = sends.join_next_with_id() => {
+                let (id, stream) = match sent {
+                    Ok((id, stream)) => (id, stream),
+                    Err(err) => (err.id(), None),
+                };
+                if let (Some(peer), Some(stream)) = (sending.remove(&id), stream) {
+                    connections.insert(peer, stream);
+                }
+                continue;
+            }
+        }
+        resend
+            .as_mut()
+            .reset(tokio::time::Instant::now() + interval);
+        let mut line = serde_json::to_string(&*changes.borrow_and_update())
+            .expect("a counter can always be serialized");
+        line.push('\n');
+        let line: Arc<str> = line.into();
+
+        let peers = peers.lock().unwrap().clone();
+        for peer in peers {
+            if sending.values().any(|&busy| busy == peer) {
+                continue;
+            }
+            let stream = connections.remove(&peer);
+            let send = sends.spawn(send_state(peer, stream, line.clone()));
+            sending.insert(send.id(), peer);
+        }
+    }
+    // Dropping `sends` aborts the sends still in flight.
+}
+
+/// Sends `line` to `peer`, connecting first if there's no open connection.
+///
+/// # Returns
+/// The connection to send on next time, or `None` if it couldn't be made or failed.
+async fn send_state(
+    peer: SocketAddr,
+    stream: Option<TcpStream>,
+    line: Arc<str>,
+) -> Option<TcpStream> {
+    let mut stream = match stream {
+        Some(stream) => stream,
+        None => match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(peer)).await {
+            Ok(Ok(stream)) => stream,
+            // Try again next round.
+            _ => return None,
+        },
+    };
+    match tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(line.as_bytes())).await {
+        Ok(Ok(())) => Some(stream),
+        Ok(Err(err)) => {
+            tracing::debug!(%peer, %err, "lost a peer");
+            None
+        }
+        // Part of the line may have been written, so the connection can't be reused.
+        Err(_) => {
+            tracing::debug!(%peer, "dropping a peer that stopped reading");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn node(id: &str) -> ReplicationConfig {
+        ReplicationConfig {
+            gossip_interval: Duration::from_millis(50),
+            ..ReplicationConfig::new(id, (Ipv4Addr::LOCALHOST, 0).into())
+        }
+    }
+
+    /// Starts `count` replicas on localhost, each sending to all the others.
+    async fn cluster(count: usize) -> Vec<ReplicatedHandle> {
+        let mut nodes = Vec::new();
+        for i in 0..count {
+            nodes.push(start_replicated(node(&format!("node-{i}"))).await.unwrap());
+        }
+        for a in &nodes {
+            for b in &nodes {
+                if a.node_id() != b.node_id() {
+                    a.add_peer(b.local_addr());
+                }
+            }
+        }
+        nodes
+    }
+
+    /// Waits until every replica reports `total`.
+    async fn converged(nodes: &[ReplicatedHandle], total: u64) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let mut done = true;
+                for node in nodes {
+                    done &= node.get().await.unwrap() == total;
+                }
+                if done {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the replicas did not converge");
+    }
+
+    #[test]
+    fn test_merge_takes_the_larger_count_per_node() {
+        let mut a = GCounter::default();
+        a.add("a", 3);
+        a.add("b", 1);
+        let mut b = GCounter::default();
+        b.add("b", 5);
+        b.add("c", 2);
+
+        assert!(a.merge(&b));
+        assert_eq!(a.value(), 10);
+        assert!(!a.merge(&b));
+
+        // Merging in either order gives the same result.
+        let mut c = b.clone();
+        let mut a_before = GCounter::default();
+        a_before.add("a", 3);
+        a_before.add("b", 1);
+        c.merge(&a_before);
+        assert_eq!(c, a);
+    }
+
+    #[test]
+    fn test_a_new_run_folds_in_the_last_one() {
+        let mut old = GCounter::default();
+        old.start_run("a", 1);
+        old.add("a", 5);
+        let mut new = GCounter::default();
+        new.start_run("a", 2);
+        new.add("a", 2);
+
+        // Whichever copy learns of the other, the old run's count is kept, once.
+        let mut merged = old.clone();
+        merged.merge(&new);
+        new.merge(&old);
+        assert_eq!(merged, new);
+        assert_eq!(new.value(), 7);
+        assert_eq!(
+            new.nodes()["a"],
+            NodeCount {
+                run: 2,
+                earlier: 5,
+                count: 2
+            }
+        );
+        assert!(!new.merge(&old));
+    }
+
+    #[test]
+    fn test_merge_leaves_out_entries_over_the_limits() {
+        let mut counter = GCounter::default();
+        let mut long_id = GCounter::default();
+        long_id.add(&"x".repeat(MAX_NODE_ID_LENGTH + 1), 1);
+        assert!(!counter.merge(&long_id));
+
+        let mut big = GCounter::default();
+        for i in 0..MAX_NODES + 1 {
+            big.add(&format!("node-{i}"), 1);
+        }
+        assert!(counter.merge(&big));
+        assert_eq!(counter.nodes().len(), MAX_NODES);
+
+        let mut counter = GCounter::default();
+        counter.add("a", 1);
+        let mut forged = GCounter::default();
+        forged.add("a", u64::MAX);
+        forged.add("b", 1);
+        let merged = counter.merge_known_nodes(&forged, 10);
+        assert_eq!(
+            merged,
+            Merged {
+                changed: false,
+                rejected: 2
+            }
+        );
+        assert_eq!(counter.value(), 1);
+
+        let mut small = GCounter::default();
+        small.add("a", 11);
+        assert!(counter.merge_known_nodes(&small, 10).changed);
+        assert_eq!(counter.value(), 11);
+    }
+
+    #[tokio::test]
+    async fn test_replicas_converge_to_the_global_total() {
+        let nodes = cluster(3).await;
+        for (i, node) in nodes.iter().enumerate() {
+            node.add(i as u64 + 1).await.unwrap();
+            node.increment().await.unwrap();
+        }
+        converged(&nodes, 9).await;
+
+        let state = nodes[0].state().await.unwrap();
+        assert_eq!(state.counts()["node-0"], 2);
+        assert_eq!(state.counts()["node-2"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_restarted_node_keeps_its_earlier_increments() {
+        let nodes = cluster(2).await;
+        nodes[0].add(5).await.unwrap();
+        converged(&nodes, 5).await;
+
+        // node-0 comes back under the same name, having forgotten its count.
+        nodes[0].shutdown().await;
+        let restarted = start_replicated(node("node-0")).await.unwrap();
+        restarted.add_peer(nodes[1].local_addr());
+        nodes[1].add_peer(restarted.local_addr());
+        restarted.add(2).await.unwrap();
+        converged(&[restarted.clone(), nodes[1].clone()], 7).await;
+
+        // The runs are folded into the one entry.
+        let state = restarted.state().await.unwrap();
+        assert_eq!(state.counts()["node-0"], 7);
+        assert_eq!(state.nodes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_late_peer_catches_up() {
+        let nodes = cluster(2).await;
+        nodes[0].add(5).await.unwrap();
+        nodes[1].add(7).await.unwrap();
+        converged(&nodes, 12).await;
+
+        let late = start_replicated(node("late")).await.unwrap();
+        late.add_peer(nodes[0].local_addr());
+        nodes[1].add_peer(late.local_addr());
+        // `late` learns about node-0 and node-1 from node-1's periodic resends.
+        converged(std::slice::from_ref(&late), 12).await;
+        late.increment().await.unwrap();
+        converged(&nodes, 13).await;
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peers_are_retried() {
+        let a = start_replicated(node("a")).await.unwrap();
+        let reserved = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let b_addr = reserved.local_addr().unwrap();
+        drop(reserved);
+
+        a.add_peer(b_addr);
+        a.add(4).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // `b` comes up on the address `a` has been failing to reach.
+        let b = start_replicated(ReplicationConfig {
+            listen: b_addr,
+            peers: vec![a.local_addr()],
+            ..node("b")
+        })
+        .await
+        .unwrap();
+        converged(&[b], 4).await;
+    }
+
+    #[tokio::test]
+    async fn test_peers_sending_oversized_states_are_dropped() {
+        use tokio::io::AsyncReadExt;
+
+        let a = start_replicated(node("a")).await.unwrap();
+        let mut peer = TcpStream::connect(a.local_addr()).await.unwrap();
+        // No newline, however much is sent.
+        let state = vec![b'x'; MAX_STATE_LENGTH + 1];
+        peer.write_all(&state).await.unwrap();
+
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), peer.read(&mut buf))
+            .await
+            .expect("the peer was not dropped");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(a.get().await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_addresses_cant_add_nodes() {
+        let a = start_replicated(node("a")).await.unwrap();
+        // Not one of `a`'s peers, so its state is for nodes `a` doesn't know about.
+        let b = start_replicated(node("b")).await.unwrap();
+        b.add_peer(a.local_addr());
+        b.add(4).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(a.get().await, Ok(0));
+
+        a.add_peer(b.local_addr());
+        converged(&[a], 4).await;
+    }
+
+    /// Starts a peer that accepts connections and never reads from them.
+    async fn stalled_peer() -> SocketAddr {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        // A small buffer fills up sooner.
+        socket.set_recv_buffer_size(4096).unwrap();
+        socket.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let listener = socket.listen(16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peers_dont_hold_up_the_others() {
+        let nodes = cluster(2).await;
+        for _ in 0..3 {
+            nodes[0].add_peer(stalled_peer().await);
+        }
+        // A large state, so the stalled peers' connections soon stop taking more.
+        let mut large = GCounter::default();
+        for i in 0..MAX_NODES - 2 {
+            large.add(&format!("{i:0>width$}", width = MAX_NODE_ID_LENGTH), 1);
+        }
+        let merge = ReplicaCommand::Merge {
+            state: large,
+            from_peer: true,
+        };
+        nodes[0].actor.ask(merge).await.unwrap();
+
+        // Every increment keeps reaching node-1 promptly, for long enough that the
+        // stalled peers back up.
+        let until = tokio::time::Instant::now() + WRITE_TIMEOUT * 2;
+        while tokio::time::Instant::now() < until {
+            let total = nodes[0].increment().await.unwrap();
+            tokio::time::timeout(WRITE_TIMEOUT / 2, converged(&nodes[1..], total))
+                .await
+                .expect("node-1 was held up by the stalled peers");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_listening() {
+        let a = start_replicated(node("a")).await.unwrap();
+        let addr = a.local_addr();
+        a.shutdown().await;
+        assert_eq!(a.get().await, Err(ActorError::Closed));
+
+        // Give the accept loop a moment to notice, then the port is free again.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while TcpListener::bind(addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the listener was not closed");
+    }
+}
@@ -0,0 +1,426 @@
+//! Supervision: restarting actors that panic.
+//!
+//! A [`Supervisor`] watches the tasks running its actors. When one panics, the supervisor
+//! starts a fresh copy from the last state the actor reached before the panic. The
+//! mailbox survives the restart, so existing handles keep working.
+
+use crate::actor::{self, Actor, ActorRef, Mailbox};
+use crate::mailbox::MailboxConfig;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
+
+/// Which actors are restarted when one of them fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartStrategy {
+    /// Only the actor that failed is restarted.
+    OneForOne,
+    /// Every actor under the supervisor is restarted, for actors that depend on each other.
+    OneForAll,
+}
+
+/// How long to wait before restarting a failed actor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Restart immediately.
+    None,
+    /// Wait the same amount of time before every restart.
+    Fixed(Duration),
+    /// Double the wait for each recent restart, starting at `initial` and never exceeding `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    /// The delay before a restart, given how many restarts happened recently (including this one).
+    fn delay(&self, recent_restarts: usize) -> Duration {
+        match *self {
+            Backoff::None => Duration::ZERO,
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let doublings = recent_restarts.saturating_sub(1).min(16) as u32;
+                initial.saturating_mul(1 << doublings).min(max)
+            }
+        }
+    }
+}
+
+/// Settings for a [`Supervisor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupervisorConfig {
+    /// Which actors to restart when one fails.
+    pub strategy: RestartStrategy,
+    /// The most restarts allowed within `window`. One more and the supervisor gives up.
+    pub max_restarts: usize,
+    /// The period over which restarts are counted.
+    pub window: Duration,
+    /// How long to wait before each restart.
+    pub backoff: Backoff,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            strategy: RestartStrategy::OneForOne,
+            max_restarts: 3,
+            window: Duration::from_secs(5),
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(10),
+                max: Duration::from_secs(1),
+            },
+        }
+    }
+}
+
+/// Something that happened to a supervised actor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// The actor panicked while handling a message.
+    Panicked { name: String, message: String },
+    /// The actor was started again from its last snapshot.
+    Restarted { name: String, restarts: usize },
+    /// Every handle to the actor was dropped, so it stopped normally.
+    Stopped { name: String },
+    /// Too many restarts happened within the window, so the supervisor stopped all its actors.
+    GaveUp { name: String },
+}
+
+/// The number of events that can be buffered for each subscriber.
+const EVENT_CAPACITY: usize = 64;
+
+/// A supervised actor, with everything needed to start it again.
+trait Child: Send {
+    fn name(&self) -> &str;
+
+    /// Starts the actor from its latest snapshot.
+    fn start(&mut self, tasks: &mut JoinSet<()>) -> tokio::task::Id;
+}
+
+struct ChildSpec<A: Actor, F> {
+    name: String,
+    make_actor: F,
+    snapshot: Arc<Mutex<A::State>>,
+    mailbox: Arc<tokio::sync::Mutex<Mailbox<A>>>,
+}
+
+impl<A, F> Child for ChildSpec<A, F>
+where
+    A: Actor,
+    A::State: Clone,
+    F: Fn() -> A + Send + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, tasks: &mut JoinSet<()>) -> tokio::task::Id {
+        let actor = (self.make_actor)();
+        let state = self.snapshot.lock().unwrap().clone();
+        let snapshot = self.snapshot.clone();
+        let mailbox = self.mailbox.clone();
+
+        tasks
+            .spawn(async move {
+                // The lock is released if the task panics, so the next copy can take over.
+                let mut mailbox = mailbox.lock().await;
+                actor::run(actor, state, &mut mailbox, |state| {
+                    *snapshot.lock().unwrap() = state.clone();
+                })
+                .await;
+            })
+            .id()
+    }
+}
+
+/// Watches a group of actors and restarts them when they panic.
+///
+/// Add actors with [`Supervisor::supervise`], then call [`Supervisor::start`].
+pub struct Supervisor {
+    config: SupervisorConfig,
+    children: Vec<Box<dyn Child>>,
+    events: broadcast::Sender<SupervisorEvent>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor with no actors.
+    pub fn new(config: SupervisorConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            config,
+            children: Vec::new(),
+            events,
+        }
+    }
+
+    /// Adds an actor to the supervisor and returns a handle to it.
+    ///
+    /// Messages sent before [`Supervisor::start`] is called wait in the actor's mailbox.
+    ///
+    /// # Arguments
+    /// * `name` - A name for the actor, used in events
+    /// * `make_actor` - Creates the actor's behavior; called again on every restart
+    /// * `state` - The initial state, replaced by the latest snapshot on restart
+    pub fn supervise<A, F>(&mut self, name: &str, make_actor: F, state: A::State) -> ActorRef<A>
+    where
+        A: Actor,
+        A::State: Clone,
+        F: Fn() -> A + Send + 'static,
+    {
+        self.supervise_with(name, make_actor, state, MailboxConfig::default())
+    }
+
+    /// Like [`Supervisor::supervise`], but with a mailbox of the given size and overflow
+    /// policy. The mailbox is kept across restarts.
+    ///
+    /// # Panics
+    /// Panics if the configured capacity or batch size is 0.
+    pub fn supervise_with<A, F>(
+        &mut self,
+        name: &str,
+        make_actor: F,
+        state: A::State,
+        mailbox: MailboxConfig,
+    ) -> ActorRef<A>
+    where
+        A: Actor,
+        A::State: Clone,
+        F: Fn() -> A + Send + 'static,
+    {
+        let (actor_ref, mailbox) = actor::mailbox(mailbox);
+        self.children.push(Box::new(ChildSpec {
+            name: name.to_string(),
+            make_actor,
+            snapshot: Arc::new(Mutex::new(state)),
+            mailbox: Arc::new(tokio::sync::Mutex::new(mailbox)),
+        }));
+        actor_ref
+    }
+
+    /// Subscribes to events about the supervised actors.
+    pub fn subscribe(&self) -> broadcast::Receiver<SupervisorEvent> {
+        self.events.subscribe()
+    }
+
+    /// Starts every actor and supervises them in a background task.
+    ///
+    /// The task finishes once every actor has stopped, or when the supervisor gives up.
+    pub fn start(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    async fn run(mut self) {
+        let mut tasks = JoinSet::new();
+        let mut running: Vec<Option<tokio::task::Id>> = self
+            .children
+            .iter_mut()
+            .map(|child| Some(child.start(&mut tasks)))
+            .collect();
+        let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+
+        while let Some(result) = tasks.join_next_with_id().await {
+            let (id, error) = match result {
+                Ok((id, ())) => (id, None),
+                Err(error) => (error.id(), Some(error)),
+            };
+            let Some(index) = running.iter().position(|task| *task == Some(id)) else {
+                continue;
+            };
+            running[index] = None;
+            let name = self.children[index].name().to_string();
+
+            let Some(error) = error else {
+                let _ = self.events.send(SupervisorEvent::Stopped { name });
+                continue;
+            };
+            let _ = self.events.send(SupervisorEvent::Panicked {
+                name: name.clone(),
+                message: panic_message(error),
+            });
+
+            let now = Instant::now();
+            recent_restarts.retain(|at| now.duration_since(*at) < self.config.window);
+            if recent_restarts.len() >= self.config.max_restarts {
+                let _ = self.events.send(SupervisorEvent::GaveUp { name });
+                tasks.shutdown().await;
+                return;
+            }
+            recent_restarts.push_back(now);
+            tokio::time::sleep(self.config.backoff.delay(recent_restarts.len())).await;
+
+            let to_restart = match self.config.strategy {
+                RestartStrategy::OneForOne => vec![index],
+                RestartStrategy::OneForAll => {
+                    // Stop the siblings first, so they release their mailboxes.
+                    tasks.shutdown().await;
+                    let mut to_restart = vec![index];
+                    for (other, task) in running.iter_mut().enumerate() {
+                        if task.take().is_some() {
+                            to_restart.push(other);
+                        }
+                    }
+                    to_restart.sort_unstable();
+                    to_restart
+                }
+            };
+            for index in to_restart {
+                running[index] = Some(self.children[index].start(&mut tasks));
+                let _ = self.events.send(SupervisorEvent::Restarted {
+                    name: self.children[index].name().to_string(),
+                    restarts: recent_restarts.len(),
+                });
+            }
+        }
+    }
+}
+
+/// Extracts a readable message from a panicked task.
+fn panic_message(error: JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActorError;
+
+    /// A counter that panics when asked to add zero.
+    struct Fragile;
+
+    impl Actor for Fragile {
+        type State = u32;
+        type Message = u32;
+        type Reply = u32;
+
+        fn handle(&mut self, total: &mut u32, amount: u32) -> u32 {
+            assert!(amount > 0, "asked to add zero");
+            *total += amount;
+            *total
+        }
+    }
+
+    fn config(strategy: RestartStrategy) -> SupervisorConfig {
+        SupervisorConfig {
+            strategy,
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+            backoff: Backoff::None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_restores_last_snapshot() {
+        let mut supervisor = Supervisor::new(config(RestartStrategy::OneForOne));
+        let actor = supervisor.supervise("fragile", || Fragile, 10);
+        let mut events = supervisor.subscribe();
+        supervisor.start();
+
+        assert_eq!(actor.ask(5).await, Ok(15));
+        assert_eq!(actor.ask(0).await, Err(ActorError::ReplyDropped));
+        assert_eq!(actor.ask(1).await, Ok(16));
+
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SupervisorEvent::Panicked {
+                name: "fragile".to_string(),
+                message: "asked to add zero".to_string(),
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SupervisorEvent::Restarted {
+                name: "fragile".to_string(),
+                restarts: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_too_many_restarts() {
+        let mut supervisor = Supervisor::new(config(RestartStrategy::OneForOne));
+        let actor = supervisor.supervise("fragile", || Fragile, 0);
+        let mut events = supervisor.subscribe();
+        let task = supervisor.start();
+
+        for _ in 0..3 {
+            let _ = actor.ask(0).await;
+        }
+        task.await.unwrap();
+
+        assert_eq!(actor.ask(1).await, Err(ActorError::Closed));
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            last = Some(event);
+        }
+        assert_eq!(
+            last,
+            Some(SupervisorEvent::GaveUp {
+                name: "fragile".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_for_all_restarts_siblings() {
+        let mut supervisor = Supervisor::new(config(RestartStrategy::OneForAll));
+        let first = supervisor.supervise("first", || Fragile, 0);
+        let second = supervisor.supervise("second", || Fragile, 100);
+        let mut events = supervisor.subscribe();
+        supervisor.start();
+
+        assert_eq!(second.ask(1).await, Ok(101));
+        let _ = first.ask(0).await;
+
+        let mut restarted = Vec::new();
+        while restarted.len() < 2 {
+            if let SupervisorEvent::Restarted { name, .. } = events.recv().await.unwrap() {
+                restarted.push(name);
+            }
+        }
+        assert_eq!(restarted, vec!["first".to_string(), "second".to_string()]);
+
+        // Both actors carry on from where they were.
+        assert_eq!(first.ask(1).await, Ok(1));
+        assert_eq!(second.ask(1).await, Ok(102));
+    }
+
+    #[tokio::test]
+    async fn test_stops_when_handles_are_dropped() {
+        let mut supervisor = Supervisor::new(SupervisorConfig::default());
+        let actor = supervisor.supervise("fragile", || Fragile, 0);
+        let mut events = supervisor.subscribe();
+        let task = supervisor.start();
+
+        drop(actor);
+        task.await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SupervisorEvent::Stopped {
+                name: "fragile".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(10));
+        assert_eq!(backoff.delay(2), Duration::from_millis(20));
+        assert_eq!(backoff.delay(3), Duration::from_millis(40));
+        assert_eq!(backoff.delay(4), Duration::from_millis(50));
+    }
+}
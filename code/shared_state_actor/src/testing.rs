@@ -0,0 +1,323 @@
+//! Helpers for testing code built on actors, enabled by the `test-util` feature.
+//!
+//! Everything here works with Tokio's paused clock, which the feature also enables. In a
+//! test marked `#[tokio::test(start_paused = true)]`, time only moves when every task is
+//! waiting on a timer, and then jumps straight to the next one. Timeouts, deadlines and
+//! supervisor backoff then happen instantly and always in the same order, so tests of
+//! them neither sleep nor flake. Use [`unstarted`] to get an actor that never answers
+//! until you let it.
+
+use crate::actor::{self, Actor, ActorError, ActorRef, Mailbox, Priority};
+use crate::mailbox::MailboxConfig;
+use std::sync::{Arc, Mutex};
+
+/// The messages an actor has handled, in the order it handled them.
+///
+/// Cloning a `Recording` gives another view of the same messages.
+#[derive(Debug)]
+pub struct Recording<M> {
+    messages: Arc<Mutex<Vec<M>>>,
+}
+
+impl<M> Clone for Recording<M> {
+    fn clone(&self) -> Self {
+        Self {
+            messages: self.messages.clone(),
+        }
+    }
+}
+
+impl<M> Default for Recording<M> {
+    fn default() -> Self {
+        Self {
+            messages: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<M> Recording<M> {
+    fn push(&self, message: M) {
+        self.messages.lock().unwrap().push(message);
+    }
+
+    /// Returns a copy of the messages recorded so far.
+    pub fn messages(&self) -> Vec<M>
+    where
+        M: Clone,
+    {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the messages recorded so far.
+    pub fn take(&self) -> Vec<M> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+
+    /// The number of messages recorded so far.
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    /// Returns `true` if no messages have been recorded yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Starts recording the messages a running actor handles.
+///
+/// Messages are recorded just before they are handled, from the first one the actor
+/// handles after this call onwards. Messages dropped because their deadline passed are
+/// not recorded.
+///
+/// # Errors
+/// Returns an [`ActorError`] if the actor is not running.
+pub async fn record<A>(actor: &ActorRef<A>) -> Result<Recording<A::Message>, ActorError>
+where
+    A: Actor,
+    A::Message: Clone,
+{
+    let recording = Recording::default();
+    let tap = recording.clone();
+    actor
+        .tap(Box::new(move |message: &A::Message| {
+            tap.push(message.clone())
+        }))
+        .await?;
+    Ok(recording)
+}
+
+/// An actor that records every message and answers with whatever a closure returns.
+///
+/// Create one with [`mock`].
+pub struct MockActor<M, R> {
+    reply: Box<dyn FnMut(&M) -> R + Send>,
+    recording: Recording<M>,
+}
+
+impl<M, R> Actor for MockActor<M, R>
+where
+    M: Send + 'static,
+    R: Send + 'static,
+{
+    type State = ();
+    type Message = M;
+    type Reply = R;
+
+    fn handle(&mut self, _state: &mut (), message: M) -> R {
+        let reply = (self.reply)(&message);
+        self.recording.push(message);
+        reply
+    }
+}
+
+/// Spawns a stand-in actor that answers every message with `reply(&message)`.
+///
+/// Use it in place of a real actor to check what code sends, or to feed it canned
+/// replies.
+///
+/// # Arguments
+/// * `reply` - Works out the reply to each message. It runs on the actor's task.
+///
+/// # Returns
+/// A handle to the mock, and a recording of every message it has handled.
+pub fn mock<M, R>(
+    reply: impl FnMut(&M) -> R + Send + 'static,
+) -> (ActorRef<MockActor<M, R>>, Recording<M>)
+where
+    M: Send + 'static,
+    R: Send + 'static,
+{
+    let recording = Recording::default();
+    let actor = MockActor {
+        reply: Box::new(reply),
+        recording: recording.clone(),
+    };
+    (actor::spawn(actor, ()), recording)
+}
+
+/// A mailbox whose actor hasn't been started yet. Created by [`unstarted`].
+///
+/// Messages wait in the mailbox until [`Unstarted::start`] is called. Dropping it
+/// without starting closes the mailbox, so calls fail with [`ActorError::Closed`] or
+/// [`ActorError::ReplyDropped`].
+pub struct Unstarted<A: Actor> {
+    mailbox: Mailbox<A>,
+}
+
+impl<A: Actor> Unstarted<A> {
+    /// Starts the actor, which handles whatever is already waiting in its mailbox
+    /// first.
+    pub fn start(self, actor: A, state: A::State) -> tokio::task::JoinHandle<()> {
+        let mut mailbox = self.mailbox;
+        tokio::spawn(async move {
+            actor::run(actor, state, &mut mailbox, |_| {}).await;
+        })
+    }
+}
+
+/// Creates a handle to an actor that isn't running yet.
+///
+/// Until the actor is started, nothing sent to it is answered. This is the way to make
+/// calls time out, or to fill a mailbox, on cue.
+///
+/// # Arguments
+/// * `config` - The mailbox's capacity, what to do when it is full, and how many
+///   messages to handle at once
+///
+/// # Panics
+/// Panics if the configured capacity or batch size is 0.
+pub fn unstarted<A: Actor>(config: MailboxConfig) -> (ActorRef<A>, Unstarted<A>) {
+    let (actor_ref, mailbox) = actor::mailbox(config);
+    (actor_ref, Unstarted { mailbox })
+}
+
+/// Waits until the actor has handled every message sent to it so far and its mailbox
+/// is empty.
+///
+/// Messages that other tasks send while this waits are waited for as well, but tasks
+/// that haven't sent anything yet are not.
+///
+/// # Errors
+/// Returns an [`ActorError`] if the actor stops before its mailbox is empty.
+pub async fn settle<A: Actor>(actor: &ActorRef<A>) -> Result<(), ActorError> {
+    // The normal lane is only read once the high-priority one is empty, so a no-op sent
+    // through it comes back after everything queued ahead of it in either lane.
+    let normal = actor.with_priority(Priority::Normal);
+    loop {
+        normal.control(|_actor, _state, _observers| ()).await?;
+        if actor.metrics().queue_depth == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Returns a future that resolves once the actor's task has exited.
+///
+/// Unlike [`ActorRef::stopped`], the future doesn't hold a handle, so it can wait for
+/// an actor to stop because every handle to it was dropped.
+pub fn when_stopped<A: Actor>(actor: &ActorRef<A>) -> impl Future<Output = ()> + Send + 'static {
+    actor.when_stopped()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SupervisorEvent;
+    use crate::supervisor::{Backoff, RestartStrategy, Supervisor, SupervisorConfig};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    /// Adds to a total, and panics when asked to add zero.
+    struct Adder;
+
+    impl Actor for Adder {
+        type State = u32;
+        type Message = u32;
+        type Reply = u32;
+
+        fn handle(&mut self, total: &mut u32, amount: u32) -> u32 {
+            assert!(amount > 0, "asked to add zero");
+            *total += amount;
+            *total
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_records_and_replies() {
+        let (actor, recording) = mock(|name: &String| format!("hello {name}"));
+        assert_eq!(
+            actor.ask("ada".to_string()).await,
+            Ok("hello ada".to_string())
+        );
+        actor.tell("grace".to_string()).await.unwrap();
+        settle(&actor).await.unwrap();
+
+        assert_eq!(recording.take(), ["ada", "grace"]);
+        assert!(recording.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_record_starts_from_the_next_message() {
+        let actor = actor::spawn(Adder, 0);
+        actor.ask(1).await.unwrap();
+
+        let recording = record(&actor).await.unwrap();
+        actor.tell(2).await.unwrap();
+        actor.tell(3).await.unwrap();
+        settle(&actor).await.unwrap();
+        assert_eq!(recording.messages(), [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_settle_waits_for_a_backlog() {
+        let (actor, unstarted) = unstarted::<Adder>(MailboxConfig::default());
+        for _ in 0..10 {
+            actor.tell(1).await.unwrap();
+        }
+        assert_eq!(actor.metrics().queue_depth, 10);
+
+        unstarted.start(Adder, 0);
+        settle(&actor).await.unwrap();
+        assert_eq!(actor.metrics().queue_depth, 0);
+        assert_eq!(actor.metrics().processed, 10);
+    }
+
+    #[tokio::test]
+    async fn test_when_stopped_does_not_keep_the_actor_alive() {
+        let actor = actor::spawn(Adder, 0);
+        let stopped = when_stopped(&actor);
+        drop(actor);
+        stopped.await;
+    }
+
+    #[tokio::test]
+    async fn test_dropping_unstarted_closes_the_mailbox() {
+        let (actor, unstarted) = unstarted::<Adder>(MailboxConfig::default());
+        drop(unstarted);
+        assert_eq!(actor.ask(1).await, Err(ActorError::Closed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_with_paused_clock() {
+        let (actor, _unstarted) = unstarted::<Adder>(MailboxConfig::default());
+        let started = Instant::now();
+
+        let result = actor.with_timeout(Duration::from_secs(30)).ask(1).await;
+        assert_eq!(result, Err(ActorError::Timeout));
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_backoff_with_paused_clock() {
+        let mut supervisor = Supervisor::new(SupervisorConfig {
+            strategy: RestartStrategy::OneForOne,
+            max_restarts: 3,
+            window: Duration::from_secs(600),
+            backoff: Backoff::Fixed(Duration::from_secs(60)),
+        });
+        let actor = supervisor.supervise("adder", || Adder, 0);
+        let mut events = supervisor.subscribe();
+        supervisor.start();
+
+        assert_eq!(actor.ask(5).await, Ok(5));
+        let started = Instant::now();
+        assert_eq!(actor.ask(0).await, Err(ActorError::ReplyDropped));
+
+        // Waits in the mailbox until the backoff is over.
+        assert_eq!(actor.ask(1).await, Ok(6));
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            SupervisorEvent::Panicked { .. }
+        ));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SupervisorEvent::Restarted {
+                name: "adder".to_string(),
+                restarts: 1,
+            }
+        );
+    }
+}
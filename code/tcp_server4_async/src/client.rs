@@ -0,0 +1,283 @@
+//! A client that matches replies to requests by id.
+//!
+//! Every [`Client::call`] gets its own request id, so calls can be pipelined over the
+//! one connection and each still gets its own reply, whatever order the server answers
+//! them in. [`Client::send`] also hands back the id, which is what `cancel <id>` and
+//! `status <id>` refer to.
+
+use crate::protocol::{ClientCodec, Command, FrameError, Reply, Request, Response};
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Errors from [`Client::call`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The connection closed before the reply arrived.
+    Closed,
+    /// The request can't be sent, as the server couldn't read it back. Nothing was sent,
+    /// and the connection carries on.
+    Invalid(FrameError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Closed => write!(f, "connection closed before the reply arrived"),
+            ClientError::Invalid(err) => write!(f, "request can't be sent: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// A request waiting to be sent, and where its response goes.
+struct Call {
+    request: Request,
+    reply: oneshot::Sender<Response>,
+}
+
+/// A connection to the server. Dropping it closes the connection.
+#[derive(Debug)]
+pub struct Client {
+    calls: mpsc::Sender<Call>,
+    next_id: AtomicU64,
+}
+
+impl Client {
+    /// Connects to the server at `addr`.
+    ///
+    /// # Errors
+    /// Returns the I/O error if the connection can't be made.
+    pub async fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        let (calls, calls_rx) = mpsc::channel(32);
+        tokio::spawn(run_connection(socket, calls_rx));
+        Ok(Self {
+            calls,
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Sends `command` and waits for the reply to it.
+    ///
+    /// # Errors
+    /// Returns [`ClientError::Closed`] if the connection closes before the reply arrives,
+    /// or [`ClientError::Invalid`] if the command can't be sent.
+    pub async fn call(&self, command: Command) -> Result<Response, ClientError> {
+        self.send(command).await?.await
+    }
+
+    /// Sends `command` without waiting for the reply.
+    ///
+    /// # Returns
+    /// The call in flight, which knows its request id and resolves to the reply.
+    ///
+    /// # Errors
+    /// Returns [`ClientError::Closed`] if the connection has closed, or
+    /// [`ClientError::Invalid`] if the command has an argument that isn't a single word,
+    /// or is too long to send.
+    pub async fn send(&self, command: Command) -> Result<PendingCall, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = Request { id, command };
+        // The server would answer a line it can't read without an id, leaving the call
+        // waiting forever, so it isn't sent.
+        request.check().map_err(ClientError::Invalid)?;
+        let (reply, response) = oneshot::channel();
+        self.calls
+            .send(Call { request, reply })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        Ok(PendingCall { id, response })
+    }
+}
+
+/// A request that has been sent, resolving to the reply to it.
+#[derive(Debug)]
+pub struct PendingCall {
+    id: u64,
+    response: oneshot::Receiver<Response>,
+}
+
+impl PendingCall {
+    /// The id the request was sent with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Future for PendingCall {
+    type Output = Result<Response, ClientError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.response)
+            .poll(cx)
+            .map_err(|_| ClientError::Closed)
+    }
+}
+
+/// Sends calls and hands each reply to the call with the same id.
+///
+/// Runs until the [`Client`] is dropped or the connection closes. Calls still waiting
+/// then fail with [`ClientError::Closed`].
+async fn run_connection(socket: TcpStream, mut calls: mpsc::Receiver<Call>) {
+    let (reader, writer) = socket.into_split();
+    let mut replies = FramedRead::new(reader, ClientCodec::new());
+    let mut requests = FramedWrite::new(writer, ClientCodec::new());
+
+    let mut pending: HashMap<u64, oneshot::Sender<Response>> = HashMap::new();
+    loop {
+        tokio::select! {
+            call = calls.recv() => {
+                let Some(Call { request, reply }) = call else {
+                    break;
+                };
+                let id = request.id;
+                match requests.send(request).await {
+                    Ok(()) => {
+                        pending.insert(id, reply);
+                    }
+                    // Nothing was written, so only this call fails.
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                        eprintln!("Client refused to send request {id}: {e}");
+                    }
+                    Err(e) => {
+                        eprintln!("Client write error: {e}");
+                        break;
+                    }
+                }
+            }
+            frame = replies.next() => match frame {
+                Some(Ok(Ok(Reply { id: Some(id), response }))) => match pending.remove(&id) {
+                    // The caller may have given up waiting.
+                    Some(reply) => {
+                        let _ = reply.send(response);
+                    }
+                    None => eprintln!("Client received a reply to no request: {id}"),
+                },
+                Some(Ok(Ok(Reply { id: None, response: Response::ShuttingDown }))) => {
+                    println!("Client notified: the server is shutting down");
+                }
+                Some(Ok(Ok(Reply { id: None, response }))) => {
+                    eprintln!("Client received a reply without a request id: {response}");
+                }
+                Some(Ok(Err(e))) => eprintln!("Client received a bad line: {e}"),
+                Some(Err(e)) => {
+                    eprintln!("Client read error: {e}");
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{MAX_LINE_LENGTH, ServerCodec};
+    use tokio::net::TcpListener;
+
+    /// Accepts one connection and answers the first `count` requests in reverse order.
+    async fn reversing_server(count: usize) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, writer) = socket.into_split();
+            let mut requests = FramedRead::new(reader, ServerCodec::new());
+            let mut replies = FramedWrite::new(writer, ServerCodec::new());
+
+            let mut received = Vec::new();
+            while received.len() < count {
+                received.push(requests.next().await.unwrap().unwrap().unwrap());
+            }
+            for Request { id, command } in received.into_iter().rev() {
+                let response = match command.name.as_str() {
+                    "calculate" => Response::CalculationComplete,
+                    _ => Response::Hello,
+                };
+                replies
+                    .send(Reply {
+                        id: Some(id),
+                        response,
+                    })
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_calls_get_their_own_replies() {
+        let client = Client::connect(reversing_server(3).await).await.unwrap();
+
+        let (calculation, hello, again) = tokio::join!(
+            client.call(Command::new("calculate")),
+            client.call(Command::new("hello")),
+            client.call(Command::new("hello")),
+        );
+        assert_eq!(calculation, Ok(Response::CalculationComplete));
+        assert_eq!(hello, Ok(Response::Hello));
+        assert_eq!(again, Ok(Response::Hello));
+    }
+
+    #[tokio::test]
+    async fn test_sent_calls_know_their_id() {
+        let client = Client::connect(reversing_server(2).await).await.unwrap();
+
+        let first = client.send(Command::new("calculate")).await.unwrap();
+        let second = client.send(Command::new("hello")).await.unwrap();
+        assert_eq!((first.id(), second.id()), (1, 2));
+        assert_eq!(second.await, Ok(Response::Hello));
+        assert_eq!(first.await, Ok(Response::CalculationComplete));
+    }
+
+    #[tokio::test]
+    async fn test_unsendable_calls_fail_alone() {
+        let client = Client::connect(reversing_server(1).await).await.unwrap();
+
+        let newline = Command::new("cancel").arg("1\n2 hello");
+        assert_eq!(
+            client.call(newline).await,
+            Err(ClientError::Invalid(FrameError::InvalidWord(
+                "1\n2 hello".to_string()
+            )))
+        );
+        let long = Command::new("hello").arg("x".repeat(MAX_LINE_LENGTH));
+        assert_eq!(
+            client.call(long).await,
+            Err(ClientError::Invalid(FrameError::TooLong))
+        );
+        // The connection is still there for the next call.
+        assert_eq!(
+            client.call(Command::new("hello")).await,
+            Ok(Response::Hello)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_calls_fail_when_the_connection_closes() {
+        // The server reads the request and goes away without answering it.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut requests = FramedRead::new(socket, ServerCodec::new());
+            requests.next().await;
+        });
+
+        let client = Client::connect(addr).await.unwrap();
+        assert_eq!(
+            client.call(Command::new("hello")).await,
+            Err(ClientError::Closed)
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! The commands the server understands, registered by name.
+//!
+//! A command is registered with its usage, such as `cancel <id>`, a line of help, and an
+//! async handler. The registry checks the arguments against the usage before calling the
+//! handler, answers `help` itself, and replies with an error if the command is unknown or
+//! the handler fails, so the connection loop only has to hand it each request.
+//!
+//! ```ignore
+//! let mut commands = CommandRegistry::new();
+//! commands.register("echo <word>", "Says <word> back", |call| async move {
+//!     let word = call.args.get("word").unwrap_or_default().to_string();
+//!     call.reply.send(Response::Ok(word)).await;
+//!     Ok(())
+//! });
+//! ```
+
+use crate::protocol::{Reply, Request, Response};
+use crate::tasks::{TaskError, Tasks};
+use futures::future::BoxFuture;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Why a command couldn't be carried out. Sent to the client as [`Response::Error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// No command is registered under the name.
+    Unknown(String),
+    /// The arguments didn't match the command's usage, which is included.
+    Usage(String),
+    /// An argument didn't parse.
+    InvalidArgument { name: String, value: String },
+    /// The handler couldn't do what was asked.
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "unknown command {name}, try help"),
+            CommandError::Usage(usage) => write!(f, "usage: {usage}"),
+            CommandError::InvalidArgument { name, value } => {
+                write!(f, "invalid <{name}>: {value}")
+            }
+            CommandError::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<TaskError> for CommandError {
+    fn from(err: TaskError) -> Self {
+        CommandError::Failed(err.to_string())
+    }
+}
+
+/// Sends responses to one request.
+#[derive(Debug, Clone)]
+pub struct ReplySender {
+    id: u64,
+    replies: mpsc::Sender<Reply>,
+}
+
+impl ReplySender {
+    /// The id of the request being answered.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Sends `response` to the client. Nothing happens if the connection has closed.
+    pub async fn send(&self, response: Response) {
+        let reply = Reply {
+            id: Some(self.id),
+            response,
+        };
+        let _ = self.replies.send(reply).await;
+    }
+}
+
+/// The arguments of a command, by the names in its usage.
+#[derive(Debug, Clone)]
+pub struct Args {
+    usage: Arc<Usage>,
+    values: Vec<String>,
+}
+
+impl Args {
+    /// The argument called `name`, or `None` if it is optional and was left out.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let index = self
+            .usage
+            .params
+            .iter()
+            .position(|param| param.name == name)?;
+        self.values.get(index).map(String::as_str)
+    }
+
+    /// Parses the argument called `name`.
+    ///
+    /// # Errors
+    /// Returns [`CommandError::InvalidArgument`] if it doesn't parse as a `T`, or
+    /// [`CommandError::Usage`] if it was left out.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, CommandError> {
+        let value = self
+            .get(name)
+            .ok_or_else(|| CommandError::Usage(self.usage.to_string()))?;
+        value.parse().map_err(|_| CommandError::InvalidArgument {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Everything a handler is given.
+pub struct Call {
+    pub args: Args,
+    pub reply: ReplySender,
+    /// The long-running work of the connection the request came from.
+    pub tasks: Tasks,
+}
+
+type Handler = Arc<dyn Fn(Call) -> BoxFuture<'static, Result<(), CommandError>> + Send + Sync>;
+
+#[derive(Debug)]
+struct Param {
+    name: String,
+    optional: bool,
+}
+
+/// A command's name and parameters, parsed from something like `help [command]`.
+#[derive(Debug)]
+struct Usage {
+    name: String,
+    params: Vec<Param>,
+}
+
+impl Usage {
+    /// # Panics
+    /// Panics if `usage` has no name, a parameter isn't written as `<name>` or `[name]`,
+    /// or an optional parameter comes before a required one.
+    fn parse(usage: &str) -> Self {
+        let mut words = usage.split_whitespace();
+        let name = words.next().expect("a command needs a name").to_lowercase();
+        let params: Vec<Param> = words
+            .map(|word| {
+                let (optional, name) = if let Some(name) = word.strip_prefix('<') {
+                    (false, name.strip_suffix('>'))
+                } else if let Some(name) = word.strip_prefix('[') {
+                    (true, name.strip_suffix(']'))
+                } else {
+                    (false, None)
+                };
+                let name = name.unwrap_or_else(|| {
+                    panic!("parameter {word} of {usage} must be <name> or [name]")
+                });
+                Param {
+                    name: name.to_string(),
+                    optional,
+                }
+            })
+            .collect();
+        assert!(
+            params.is_sorted_by_key(|param| param.optional),
+            "optional parameters of {usage} must come last"
+        );
+        Self { name, params }
+    }
+
+    /// Checks that `values` has one argument for each required parameter, and none
+    /// that the usage doesn't name.
+    fn accepts(&self, values: &[String]) -> bool {
+        let required = self.params.iter().filter(|param| !param.optional).count();
+        (required..=self.params.len()).contains(&values.len())
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for param in &self.params {
+            match param.optional {
+                true => write!(f, " [{}]", param.name)?,
+                false => write!(f, " <{}>", param.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Action {
+    Help,
+    Run(Handler),
+}
+
+struct Registered {
+    usage: Arc<Usage>,
+    help: String,
+    action: Action,
+}
+
+/// The commands a server understands, shared by all its connections.
+///
+/// `help` is always registered: on its own it lists every command, and `help <command>`
+/// describes one.
+pub struct CommandRegistry {
+    commands: BTreeMap<String, Registered>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandRegistry {
+    /// A registry that only knows `help`.
+    pub fn new() -> Self {
+        let mut registry = Self {
+            commands: BTreeMap::new(),
+        };
+        registry.insert(
+            "help [command]",
+            "Lists the commands, or describes one",
+            Action::Help,
+        );
+        registry
+    }
+
+    /// Registers a command.
+    ///
+    /// Handlers run one at a time for each connection, in the order the requests arrive,
+    /// so a handler that takes a while should hand its work to [`Call::tasks`].
+    ///
+    /// # Arguments
+    /// * `usage` - The name followed by the parameters, each written `<name>` if it is
+    ///   required or `[name]` if it is optional, such as `cancel <id>`.
+    /// * `help` - What the command does, shown by `help`.
+    /// * `handler` - Called with the arguments checked against `usage`. It replies through
+    ///   [`Call::reply`], and its error is sent to the client.
+    ///
+    /// # Panics
+    /// Panics if `usage` is malformed, or a command with the same name is registered.
+    pub fn register<F, Fut>(&mut self, usage: &str, help: &str, handler: F)
+    where
+        F: Fn(Call) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), CommandError>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |call| Box::pin(handler(call)));
+        self.insert(usage, help, Action::Run(handler));
+    }
+
+    fn insert(&mut self, usage: &str, help: &str, action: Action) {
+        let usage = Usage::parse(usage);
+        assert!(
+            !self.commands.contains_key(&usage.name),
+            "command {} is already registered",
+            usage.name
+        );
+        let registered = Registered {
+            usage: Arc::new(usage),
+            help: help.to_string(),
+            action,
+        };
+        self.commands
+            .insert(registered.usage.name.clone(), registered);
+    }
+
+    /// Carries out `request` and replies to it, unless its handler leaves that to a task.
+    ///
+    /// # Arguments
+    /// * `replies` - Where the replies for the connection go.
+    /// * `tasks` - The connection's long-running work.
+    pub async fn dispatch(&self, request: Request, replies: &mpsc::Sender<Reply>, tasks: &Tasks) {
+        let reply = ReplySender {
+            id: request.id,
+            replies: replies.clone(),
+        };
+        let Request { command, .. } = request;
+        let result = match self.commands.get(&command.name) {
+            None => Err(CommandError::Unknown(command.name)),
+            Some(registered) if !registered.usage.accepts(&command.args) => {
+                Err(CommandError::Usage(registered.usage.to_string()))
+            }
+            Some(registered) => {
+                let args = Args {
+                    usage: registered.usage.clone(),
+                    values: command.args,
+                };
+                match &registered.action {
+                    Action::Help => match self.help(&args) {
+                        Ok(help) => {
+                            reply.send(help).await;
+                            Ok(())
+                        }
+                        Err(err) => Err(err),
+                    },
+                    Action::Run(handler) => {
+                        let call = Call {
+                            args,
+                            reply: reply.clone(),
+                            tasks: tasks.clone(),
+                        };
+                        handler(call).await
+                    }
+                }
+            }
+        };
+        if let Err(err) = result {
+            reply.send(Response::Error(err.to_string())).await;
+        }
+    }
+
+    /// The text of `help`, or of `help <command>`.
+    fn help(&self, args: &Args) -> Result<Response, CommandError> {
+        let Some(name) = args.get("command") else {
+            let usages: Vec<String> = self
+                .commands
+                .values()
+                .map(|registered| registered.usage.to_string())
+                .collect();
+            return Ok(Response::Ok(format!("commands: {}", usages.join(", "))));
+        };
+        let registered = self
+            .commands
+            .get(&name.to_lowercase())
+            .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+        Ok(Response::Ok(format!(
+            "{} - {}",
+            registered.usage, registered.help
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Command;
+
+    fn registry() -> CommandRegistry {
+        let mut commands = CommandRegistry::new();
+        commands.register("hello", "Says hello back", |call| async move {
+            call.reply.send(Response::Hello).await;
+            Ok(())
+        });
+        commands.register("add <a> [b]", "Adds two numbers", |call| async move {
+            let a: u64 = call.args.parse("a")?;
+            let b: u64 = match call.args.get("b") {
+                Some(_) => call.args.parse("b")?,
+                None => 1,
+            };
+            call.reply.send(Response::Ok((a + b).to_string())).await;
+            Ok(())
+        });
+        commands.register("fail", "Always fails", |_| async {
+            Err(CommandError::Failed("as expected".to_string()))
+        });
+        commands
+    }
+
+    /// Dispatches `line` as request 1 and returns the response.
+    async fn run(commands: &CommandRegistry, line: &str) -> Response {
+        let (replies, mut replies_rx) = mpsc::channel(8);
+        let tasks = Tasks::new(replies.clone());
+        let request = Request {
+            id: 1,
+            command: line.parse::<Command>().unwrap(),
+        };
+        commands.dispatch(request, &replies, &tasks).await;
+        let reply = replies_rx.recv().await.unwrap();
+        assert_eq!(reply.id, Some(1));
+        reply.response
+    }
+
+    fn error(message: &str) -> Response {
+        Response::Error(message.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_dispatches_by_name() {
+        let commands = registry();
+        assert_eq!(run(&commands, "HELLO").await, Response::Hello);
+        assert_eq!(
+            run(&commands, "add 2 3").await,
+            Response::Ok("5".to_string())
+        );
+        assert_eq!(run(&commands, "add 2").await, Response::Ok("3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_checks_arguments_against_usage() {
+        let commands = registry();
+        assert_eq!(run(&commands, "add").await, error("usage: add <a> [b]"));
+        assert_eq!(
+            run(&commands, "add 1 2 3").await,
+            error("usage: add <a> [b]")
+        );
+        assert_eq!(run(&commands, "hello there").await, error("usage: hello"));
+        assert_eq!(run(&commands, "add 1 x").await, error("invalid <b>: x"));
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_replied() {
+        let commands = registry();
+        assert_eq!(
+            run(&commands, "fly").await,
+            error("unknown command fly, try help")
+        );
+        assert_eq!(run(&commands, "fail").await, error("as expected"));
+    }
+
+    #[tokio::test]
+    async fn test_help_lists_and_describes_commands() {
+        let commands = registry();
+        assert_eq!(
+            run(&commands, "help").await,
+            Response::Ok("commands: add <a> [b], fail, hello, help [command]".to_string())
+        );
+        assert_eq!(
+            run(&commands, "help ADD").await,
+            Response::Ok("add <a> [b] - Adds two numbers".to_string())
+        );
+        assert_eq!(
+            run(&commands, "help fly").await,
+            error("unknown command fly, try help")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered")]
+    fn test_names_are_unique() {
+        let mut commands = registry();
+        commands.register("Hello", "Again", |_| async { Ok(()) });
+    }
+
+    #[test]
+    #[should_panic(expected = "must come last")]
+    fn test_optional_parameters_come_last() {
+        CommandRegistry::new().register("add [a] <b>", "Backwards", |_| async { Ok(()) });
+    }
+
+    #[test]
+    #[should_panic(expected = "must be <name> or [name]")]
+    fn test_parameters_are_bracketed() {
+        CommandRegistry::new().register("add a", "Unbracketed", |_| async { Ok(()) });
+    }
+}
@@ -1,127 +1,151 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::select;
-use tokio::sync::mpsc;
+mod client;
+mod commands;
+mod protocol;
+mod server;
+mod tasks;
+
+use client::Client;
+use commands::CommandRegistry;
+use protocol::{Command, Response};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+const ADDR: &str = "127.0.0.1:3001";
+
+/// How long running calculations get to finish once the server is shutting down.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
 
 // ==========================
 // SERVER
 // ==========================
-async fn calculator_task(tx: mpsc::Sender<String>) {
+async fn calculator_task() -> Response {
     // Simulate a long calculation
-    tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-    let _ = tx.send("Calculation complete!\n".to_string()).await;
-}
-
-async fn server() {
-    let listener = TcpListener::bind("127.0.0.1:3001").await.unwrap();
-    println!("Server listening on 127.0.0.1:3001");
-
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        tokio::spawn(handle_connection(socket));
-    }
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    Response::CalculationComplete
 }
 
-async fn handle_connection(socket: TcpStream) {
-    // Split socket into read/write halves
-    let (reader, mut writer) = socket.into_split();
-    let mut reader = BufReader::new(reader);
-
-    // Channel for sending messages to the write half
-    let (reply_tx, mut reply_rx) = mpsc::channel::<String>(32);
-
-    // Spawn a task to write messages from the channel
-    let write_task = tokio::spawn(async move {
-        while let Some(msg) = reply_rx.recv().await {
-            println!("Server sending: {}", msg.trim());
-            if let Err(e) = writer.write_all(msg.as_bytes()).await {
-                eprintln!("Write error: {e}");
-                break;
-            }
-        }
+/// The commands the server understands. New ones only need registering here.
+fn commands() -> CommandRegistry {
+    let mut commands = CommandRegistry::new();
+    commands.register(
+        "calculate",
+        "Starts a long calculation, which replies when it's done",
+        |call| async move {
+            call.tasks.spawn(call.reply.id(), calculator_task())?;
+            Ok(())
+        },
+    );
+    commands.register("hello", "Says hello back", |call| async move {
+        call.reply.send(Response::Hello).await;
+        Ok(())
     });
-
-    // Read loop
-    let mut line = String::new();
-    loop {
-        line.clear();
-        let n = reader.read_line(&mut line).await.unwrap();
-        if n == 0 {
-            break; // connection closed
-        }
-
-        let command = line.trim().to_lowercase();
-        match command.as_str() {
-            "calculate" => {
-                println!("Server received: calculate");
-                let tx_clone = reply_tx.clone();
-                tokio::spawn(calculator_task(tx_clone));
-            }
-            "hello" => {
-                println!("Server received: hello");
-                let _ = reply_tx.send("Hello to you too!\n".to_string()).await;
-            }
-            _ => {
-                println!("Server received unknown command: {}", command);
-            }
-        }
-    }
-
-    // Close writer task
-    drop(reply_tx);
-    write_task.await.unwrap();
+    commands.register(
+        "cancel <id>",
+        "Cancels the calculation started by request <id>",
+        |call| async move {
+            let status = call.tasks.cancel(call.args.parse("id")?)?;
+            call.reply.send(Response::Status(status)).await;
+            Ok(())
+        },
+    );
+    commands.register(
+        "status <id>",
+        "Tells how the calculation started by request <id> is doing",
+        |call| async move {
+            let status = call.tasks.status(call.args.parse("id")?)?;
+            call.reply.send(Response::Status(status)).await;
+            Ok(())
+        },
+    );
+    commands
 }
 
 // ==========================
 // CLIENT
 // ==========================
 async fn client() {
-    let socket = TcpStream::connect("127.0.0.1:3001").await.unwrap();
-    let (reader, mut writer) = socket.into_split();
-    let mut reader = BufReader::new(reader);
-
-    // Task to read from the server continuously
-    let read_task = tokio::spawn(async move {
-        let mut line = String::new();
-        loop {
-            line.clear();
-            match reader.read_line(&mut line).await {
-                Ok(0) => break,
-                Ok(_) => print!("Client received: {}", line),
-                Err(e) => {
-                    eprintln!("Client read error: {e}");
-                    break;
-                }
-            }
-        }
-    });
-
-    // Task to send messages without blocking
-    let write_task = tokio::spawn(async move {
-        writer.write_all(b"calculate\n").await.unwrap();
-        println!("Client sent: calculate");
+    let client = Client::connect(ADDR).await.unwrap();
 
+    // Both calls are in flight at once, and each gets its own reply
+    let calculation = call(&client, Command::new("calculate"));
+    let greeting = async {
         // Do something else while the calculation is in progress
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        call(&client, Command::new("hello")).await;
+        call(&client, Command::new("help")).await;
+    };
+
+    // A second calculation, which is given up on
+    let abandoned = async {
+        let calculation = client.send(Command::new("calculate")).await.unwrap();
+        println!("Client sent: calculate, as request {}", calculation.id());
+        call(&client, Command::new("status").arg(calculation.id())).await;
+        call(&client, Command::new("cancel").arg(calculation.id())).await;
+        match calculation.await {
+            Ok(response) => println!("Client received for the second calculate: {response}"),
+            Err(e) => eprintln!("Client call calculate failed: {e}"),
+        }
+    };
 
-        writer.write_all(b"hello\n").await.unwrap();
-        println!("Client sent: hello");
-    });
+    tokio::join!(calculation, greeting, abandoned);
+}
 
-    let _ = tokio::join!(read_task, write_task);
+async fn call(client: &Client, command: Command) {
+    println!("Client sent: {command}");
+    match client.call(command.clone()).await {
+        Ok(response) => println!("Client received for {command}: {response}"),
+        Err(e) => eprintln!("Client call {command} failed: {e}"),
+    }
+}
+
+/// Starts a calculation, then shuts the server down while it is running.
+async fn client_during_shutdown(shutdown: CancellationToken) {
+    let client = Client::connect(ADDR).await.unwrap();
+    let calculation = client.send(Command::new("calculate")).await.unwrap();
+    println!("Client sent: calculate, as request {}", calculation.id());
+    // Once this is answered, the calculation has started
+    call(&client, Command::new("status").arg(calculation.id())).await;
+
+    shutdown.cancel();
+    match calculation.await {
+        Ok(response) => println!("Client received for calculate: {response}"),
+        Err(e) => eprintln!("Client call calculate failed: {e}"),
+    }
 }
 
 // ==========================
 // MAIN
 // ==========================
+/// Cancels `shutdown` on ctrl-c.
+fn cancel_on_ctrl_c(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Received ctrl-c");
+            shutdown.cancel();
+        }
+    });
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    // Start server
-    tokio::spawn(server());
-
-    // Wait a bit for server to start
-    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-
-    // Run client
+    // Start server, which stops on ctrl-c or once the clients are done
+    let listener = TcpListener::bind(ADDR).await.unwrap();
+    println!("Server listening on {ADDR}");
+    let shutdown = CancellationToken::new();
+    cancel_on_ctrl_c(shutdown.clone());
+    let server = tokio::spawn(server::server(
+        listener,
+        Arc::new(commands()),
+        shutdown.clone(),
+        GRACE_PERIOD,
+    ));
+
+    // Run clients
     client().await;
+    client_during_shutdown(shutdown).await;
+
+    let summary = server.await.unwrap();
+    println!("Server shut down: {summary}");
 }
@@ -0,0 +1,588 @@
+//! The line protocol spoken between the server and the client.
+//!
+//! Every message is one line of UTF-8 text ending in `\n`. A [`Request`] starts with an
+//! id chosen by the client, and the [`Reply`] to it starts with the same id, so replies
+//! can arrive in any order:
+//!
+//! ```text
+//! 1 calculate
+//! 2 hello
+//!                 2 Hello to you too!
+//!                 1 Calculation complete!
+//! ```
+//!
+//! A [`Command`] is a name followed by its arguments, separated by spaces. Which names
+//! the server understands is up to its [`CommandRegistry`].
+//!
+//! Lines longer than [`MAX_LINE_LENGTH`] are refused, so a client that never sends a
+//! newline can't make the server buffer without bound.
+//!
+//! A line that is too long, isn't UTF-8, or doesn't parse is reported as a
+//! [`FrameError`] in place of that one message, and the connection carries on. Only I/O
+//! errors end the stream.
+
+#[cfg(doc)]
+use crate::commands::CommandRegistry;
+use crate::tasks::TaskStatus;
+use bytes::BytesMut;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+/// The longest line accepted, not counting the newline.
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+/// A command sent by the client: a name and its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// Always lowercase.
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl Command {
+    /// A command without arguments, which can be added with [`Command::arg`].
+    ///
+    /// The name is lowercased, as the server ignores case.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into().to_lowercase(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds an argument. It must be non-empty and can't contain whitespace, or the
+    /// command fails [`Command::check`] and can't be sent.
+    pub fn arg(mut self, arg: impl fmt::Display) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Checks that the command reads back the same once sent: its name and arguments
+    /// are non-empty and contain no whitespace.
+    ///
+    /// # Errors
+    /// Returns [`FrameError::InvalidWord`] with the first name or argument that isn't.
+    pub fn check(&self) -> Result<(), FrameError> {
+        let mut words = std::iter::once(&self.name).chain(&self.args);
+        match words.find(|word| word.is_empty() || word.contains(char::is_whitespace)) {
+            Some(word) => Err(FrameError::InvalidWord(word.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Command {
+    type Err = FrameError;
+
+    /// Splits a command into its name and arguments. The name is lowercased, and
+    /// surrounding whitespace ignored.
+    fn from_str(line: &str) -> Result<Self, FrameError> {
+        let mut words = line.split_whitespace();
+        let name = words
+            .next()
+            .ok_or_else(|| FrameError::Unrecognized(line.trim().to_string()))?;
+        Ok(Command {
+            name: name.to_lowercase(),
+            args: words.map(str::to_string).collect(),
+        })
+    }
+}
+
+/// Replies sent by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The reply to `hello`.
+    Hello,
+    /// A calculation started with `calculate` has finished.
+    CalculationComplete,
+    /// The task started by this request was cancelled, by the client or because the
+    /// connection closed.
+    Cancelled,
+    /// The status of the task asked about with `status` or `cancel`.
+    Status(TaskStatus),
+    /// Anything else a command has to say, such as the output of `help`.
+    Ok(String),
+    /// The server is shutting down, sent without a request id. No more requests are
+    /// read, but tasks already running get a while to finish.
+    ShuttingDown,
+    /// The server couldn't make sense of a line, or couldn't carry out the request.
+    Error(String),
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Hello => write!(f, "Hello to you too!"),
+            Response::CalculationComplete => write!(f, "Calculation complete!"),
+            Response::Cancelled => write!(f, "cancelled"),
+            Response::ShuttingDown => write!(f, "shutting down"),
+            Response::Status(status) => write!(f, "status: {status}"),
+            Response::Ok(text) => write!(f, "ok: {text}"),
+            Response::Error(message) => write!(f, "error: {message}"),
+        }
+    }
+}
+
+impl FromStr for Response {
+    type Err = FrameError;
+
+    fn from_str(line: &str) -> Result<Self, FrameError> {
+        let line = line.trim();
+        match line {
+            "Hello to you too!" => return Ok(Response::Hello),
+            "Calculation complete!" => return Ok(Response::CalculationComplete),
+            "cancelled" => return Ok(Response::Cancelled),
+            "shutting down" => return Ok(Response::ShuttingDown),
+            _ => {}
+        }
+        if let Some(message) = line.strip_prefix("error: ") {
+            return Ok(Response::Error(message.to_string()));
+        }
+        if let Some(text) = line.strip_prefix("ok: ") {
+            return Ok(Response::Ok(text.to_string()));
+        }
+        if let Some(Ok(status)) = line.strip_prefix("status: ").map(str::parse) {
+            return Ok(Response::Status(status));
+        }
+        Err(FrameError::Unrecognized(line.to_string()))
+    }
+}
+
+/// A [`Command`] with the id its reply will carry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// Chosen by the client, and unique among its requests in flight.
+    pub id: u64,
+    pub command: Command,
+}
+
+impl Request {
+    /// Checks that the request can be sent: its command passes [`Command::check`], and
+    /// the line fits in [`MAX_LINE_LENGTH`].
+    ///
+    /// # Errors
+    /// Returns [`FrameError::InvalidWord`] or [`FrameError::TooLong`].
+    pub fn check(&self) -> Result<(), FrameError> {
+        self.command.check()?;
+        if self.to_string().len() > MAX_LINE_LENGTH {
+            return Err(FrameError::TooLong);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.id, self.command)
+    }
+}
+
+impl FromStr for Request {
+    type Err = FrameError;
+
+    fn from_str(line: &str) -> Result<Self, FrameError> {
+        let (id, command) = split_id(line)?;
+        let id = id
+            .parse()
+            .map_err(|_| FrameError::MissingId(line.trim().to_string()))?;
+        let command = command.parse()?;
+        Ok(Request { id, command })
+    }
+}
+
+/// A [`Response`] to the request with the same id.
+///
+/// The id is `None` when the server couldn't tell which request a line was, because it
+/// was too long, wasn't UTF-8 or didn't start with an id. It is sent as `-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub id: Option<u64>,
+    pub response: Response,
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "{id} {}", self.response),
+            None => write!(f, "- {}", self.response),
+        }
+    }
+}
+
+impl FromStr for Reply {
+    type Err = FrameError;
+
+    fn from_str(line: &str) -> Result<Self, FrameError> {
+        let (id, response) = split_id(line)?;
+        let id = match id {
+            "-" => None,
+            id => Some(
+                id.parse()
+                    .map_err(|_| FrameError::MissingId(line.trim().to_string()))?,
+            ),
+        };
+        Ok(Reply {
+            id,
+            response: response.parse()?,
+        })
+    }
+}
+
+/// Splits a line into its leading id and the message after it.
+fn split_id(line: &str) -> Result<(&str, &str), FrameError> {
+    line.trim()
+        .split_once(' ')
+        .ok_or_else(|| FrameError::MissingId(line.trim().to_string()))
+}
+
+/// Why a single line couldn't be decoded. The line is skipped and the stream goes on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The line was longer than [`MAX_LINE_LENGTH`].
+    TooLong,
+    /// The line wasn't valid UTF-8.
+    InvalidUtf8,
+    /// The line didn't start with a request id.
+    MissingId(String),
+    /// The message after the id didn't parse.
+    Unrecognized(String),
+    /// A command name or argument to be sent was empty or contained whitespace.
+    InvalidWord(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLong => write!(f, "line longer than {MAX_LINE_LENGTH} bytes"),
+            FrameError::InvalidUtf8 => write!(f, "line is not valid UTF-8"),
+            FrameError::MissingId(line) => write!(f, "missing request id: {line}"),
+            FrameError::Unrecognized(message) => write!(f, "unrecognized message: {message}"),
+            FrameError::InvalidWord(word) => write!(f, "not a single word: {word:?}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Decodes lines into `In` and encodes `Out` as lines.
+///
+/// The server uses [`ServerCodec`], and the client [`ClientCodec`].
+#[derive(Debug)]
+pub struct LineCodec<In, Out> {
+    lines: LinesCodec,
+    messages: PhantomData<fn(Out) -> In>,
+}
+
+/// Reads requests and writes replies.
+pub type ServerCodec = LineCodec<Request, Reply>;
+
+/// Reads replies and writes requests.
+pub type ClientCodec = LineCodec<Reply, Request>;
+
+impl<In, Out> LineCodec<In, Out> {
+    /// A codec accepting lines of up to [`MAX_LINE_LENGTH`] bytes.
+    pub fn new() -> Self {
+        Self::with_max_length(MAX_LINE_LENGTH)
+    }
+
+    /// A codec accepting lines of up to `max_length` bytes.
+    pub fn with_max_length(max_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_length),
+            messages: PhantomData,
+        }
+    }
+
+    /// Turns what the line codec made of the buffer into a message.
+    fn parse(
+        decoded: Result<Option<String>, LinesCodecError>,
+    ) -> io::Result<Option<Result<In, FrameError>>>
+    where
+        In: FromStr<Err = FrameError>,
+    {
+        match decoded {
+            Ok(Some(line)) => Ok(Some(line.parse())),
+            Ok(None) => Ok(None),
+            // The rest of the line is skipped, up to the next newline.
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(Err(FrameError::TooLong))),
+            // The line has already been consumed.
+            Err(LinesCodecError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                Ok(Some(Err(FrameError::InvalidUtf8)))
+            }
+            Err(LinesCodecError::Io(err)) => Err(err),
+        }
+    }
+}
+
+impl<In, Out> Default for LineCodec<In, Out> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<In: FromStr<Err = FrameError>, Out> Decoder for LineCodec<In, Out> {
+    type Item = Result<In, FrameError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        Self::parse(self.lines.decode(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        Self::parse(self.lines.decode_eof(src))
+    }
+}
+
+impl<In, Out: fmt::Display> Encoder<Out> for LineCodec<In, Out> {
+    type Error = io::Error;
+
+    /// Writes `message` as one line.
+    ///
+    /// # Errors
+    /// Fails with [`io::ErrorKind::InvalidInput`], writing nothing, if the message
+    /// contains a newline or is longer than the codec accepts, as the other end would
+    /// refuse it.
+    fn encode(&mut self, message: Out, dst: &mut BytesMut) -> io::Result<()> {
+        let line = message.to_string();
+        if line.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a message can't contain a newline",
+            ));
+        }
+        // `LinesCodec` only enforces its limit when decoding.
+        if line.len() > self.lines.max_length() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                FrameError::TooLong,
+            ));
+        }
+        self.lines.encode(line, dst).map_err(|err| match err {
+            LinesCodecError::Io(err) => err,
+            LinesCodecError::MaxLineLengthExceeded => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut ServerCodec, input: &[u8]) -> Vec<Result<Request, FrameError>> {
+        let mut buffer = BytesMut::from(input);
+        let mut decoded = Vec::new();
+        while let Some(item) = codec.decode_eof(&mut buffer).unwrap() {
+            decoded.push(item);
+        }
+        decoded
+    }
+
+    fn request(id: u64, command: Command) -> Request {
+        Request { id, command }
+    }
+
+    #[test]
+    fn test_decodes_requests_ignoring_case_and_whitespace() {
+        let mut codec = ServerCodec::new();
+        assert_eq!(
+            decode_all(
+                &mut codec,
+                b"1 calculate\r\n  2 HELLO \n3 Cancel  1\n4 fly To the MOON"
+            ),
+            [
+                Ok(request(1, Command::new("calculate"))),
+                Ok(request(2, Command::new("hello"))),
+                Ok(request(3, Command::new("cancel").arg(1))),
+                Ok(request(
+                    4,
+                    Command::new("fly").arg("To").arg("the").arg("MOON")
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partial_lines_wait_for_more() {
+        let mut codec = ServerCodec::new();
+        let mut buffer = BytesMut::from(&b"7 hel"[..]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(b"lo\n");
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Ok(request(7, Command::new("hello"))))
+        );
+    }
+
+    #[test]
+    fn test_bad_lines_are_reported_and_skipped() {
+        let mut codec = ServerCodec::with_max_length(12);
+        assert_eq!(
+            decode_all(
+                &mut codec,
+                b"1 much too long a line\nhello\n\xff\xfe\n3 hello\n"
+            ),
+            [
+                Err(FrameError::TooLong),
+                Err(FrameError::MissingId("hello".to_string())),
+                Err(FrameError::InvalidUtf8),
+                Ok(request(3, Command::new("hello"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_requests_need_an_id_and_a_command() {
+        for line in ["x hello", "hello", "3", "3   "] {
+            assert_eq!(
+                line.parse::<Request>(),
+                Err(FrameError::MissingId(line.trim().to_string()))
+            );
+        }
+        assert_eq!(
+            "".parse::<Command>(),
+            Err(FrameError::Unrecognized(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_long_lines_are_refused_before_the_newline_arrives() {
+        let mut codec = ServerCodec::with_max_length(8);
+        let mut buffer = BytesMut::from(&[b'x'; 100][..]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Err(FrameError::TooLong))
+        );
+        // Nothing is kept while the rest of the line is skipped.
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_requests_round_trip() {
+        let mut client = ClientCodec::new();
+        let mut server = ServerCodec::new();
+        let mut buffer = BytesMut::new();
+        let requests = [
+            request(1, Command::new("calculate")),
+            request(2, Command::new("HELLO")),
+            request(3, Command::new("cancel").arg(1)),
+            request(4, Command::new("status").arg(1)),
+        ];
+        for request in requests.clone() {
+            client.encode(request, &mut buffer).unwrap();
+        }
+        assert_eq!(
+            &buffer[..],
+            b"1 calculate\n2 hello\n3 cancel 1\n4 status 1\n"
+        );
+
+        let mut decoded = Vec::new();
+        while let Some(item) = server.decode(&mut buffer).unwrap() {
+            decoded.push(item.unwrap());
+        }
+        assert_eq!(decoded, requests);
+    }
+
+    #[test]
+    fn test_replies_round_trip() {
+        let mut server = ServerCodec::new();
+        let mut client = ClientCodec::new();
+        let mut buffer = BytesMut::new();
+        let replies = [
+            Reply {
+                id: Some(2),
+                response: Response::Hello,
+            },
+            Reply {
+                id: Some(1),
+                response: Response::CalculationComplete,
+            },
+            Reply {
+                id: Some(3),
+                response: Response::Status(TaskStatus::Failed("it panicked".to_string())),
+            },
+            Reply {
+                id: Some(4),
+                response: Response::Cancelled,
+            },
+            Reply {
+                id: Some(5),
+                response: Response::Ok("commands: hello, help [command]".to_string()),
+            },
+            Reply {
+                id: None,
+                response: Response::ShuttingDown,
+            },
+            Reply {
+                id: None,
+                response: Response::Error("line is not valid UTF-8".to_string()),
+            },
+        ];
+        for reply in replies.clone() {
+            server.encode(reply, &mut buffer).unwrap();
+        }
+
+        let mut decoded = Vec::new();
+        while let Some(item) = client.decode(&mut buffer).unwrap() {
+            decoded.push(item.unwrap());
+        }
+        assert_eq!(decoded, replies);
+    }
+
+    #[test]
+    fn test_messages_with_newlines_are_not_sent() {
+        let mut server = ServerCodec::new();
+        let mut buffer = BytesMut::new();
+        let reply = Reply {
+            id: Some(1),
+            response: Response::Error("two\nlines".to_string()),
+        };
+        let err = server.encode(reply, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_messages_too_long_to_be_read_are_not_sent() {
+        let mut client = ClientCodec::with_max_length(12);
+        let mut buffer = BytesMut::new();
+        client
+            .encode(request(1, Command::new("hello")), &mut buffer)
+            .unwrap();
+        let long = request(2, Command::new("hello").arg("everyone"));
+        let err = client.encode(long, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(&buffer[..], b"1 hello\n");
+    }
+
+    #[test]
+    fn test_requests_are_checked_before_sending() {
+        assert_eq!(request(1, Command::new("cancel").arg(1)).check(), Ok(()));
+        for arg in ["two\nlines", "two words", ""] {
+            assert_eq!(
+                request(1, Command::new("cancel").arg(arg)).check(),
+                Err(FrameError::InvalidWord(arg.to_string()))
+            );
+        }
+        let long = "x".repeat(MAX_LINE_LENGTH);
+        assert_eq!(
+            request(1, Command::new("hello").arg(long)).check(),
+            Err(FrameError::TooLong)
+        );
+    }
+}
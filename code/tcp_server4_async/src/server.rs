@@ -0,0 +1,342 @@
+//! Accepting connections, serving them, and shutting down without dropping work.
+//!
+//! When the shutdown token is cancelled, the server stops accepting, and every open
+//! connection sends its client [`Response::ShuttingDown`] and stops reading requests.
+//! Tasks already running get the grace period to finish and reply. Whatever is still
+//! running after that is cancelled, and the connection closes.
+
+use crate::commands::CommandRegistry;
+use crate::protocol::{Reply, Response, ServerCodec};
+use crate::tasks::Tasks;
+use futures::{SinkExt, StreamExt};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
+
+/// How long connections get to close after the grace period, before they are aborted.
+///
+/// Only a client that stops reading, so that the last replies can't be written, needs it.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait after a failed accept before trying again.
+///
+/// Errors such as running out of file descriptors last until some connections close, so
+/// retrying straight away would only spin.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How a connection open at shutdown was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainOutcome {
+    /// Every task finished within the grace period.
+    Drained {
+        /// Tasks that were running at shutdown.
+        finished: usize,
+    },
+    /// The grace period ran out and the tasks still running were cancelled.
+    Forced { cancelled: usize },
+}
+
+/// A connection that was open when the server shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionReport {
+    pub peer: SocketAddr,
+    pub outcome: DrainOutcome,
+}
+
+/// What [`server`] returns once it has shut down.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// The connections that were open at shutdown, in the order they closed.
+    pub connections: Vec<ConnectionReport>,
+    /// Connections that still hadn't closed a while after the grace period, and were
+    /// aborted.
+    pub aborted: usize,
+}
+
+impl ShutdownSummary {
+    /// How many connections closed with all their tasks finished.
+    pub fn drained(&self) -> usize {
+        self.connections
+            .iter()
+            .filter(|report| matches!(report.outcome, DrainOutcome::Drained { .. }))
+            .count()
+    }
+
+    /// How many connections had tasks cancelled when the grace period ran out.
+    pub fn forced(&self) -> usize {
+        self.connections.len() - self.drained()
+    }
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections drained, {} forced closed, {} aborted",
+            self.drained(),
+            self.forced(),
+            self.aborted
+        )
+    }
+}
+
+/// Serves connections from `listener` until `shutdown` is cancelled, then drains them.
+///
+/// # Arguments
+/// * `commands` - The commands every connection understands.
+/// * `shutdown` - Cancelled to shut the server down.
+/// * `grace` - How long running tasks get to finish once shutdown begins.
+///
+/// # Returns
+/// How each connection open at shutdown was closed.
+pub async fn server(
+    listener: TcpListener,
+    commands: Arc<CommandRegistry>,
+    shutdown: CancellationToken,
+    grace: Duration,
+) -> ShutdownSummary {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    let connection =
+                        handle_connection(socket, peer, commands.clone(), shutdown.clone(), grace);
+                    connections.spawn(connection);
+                }
+                Err(e) => {
+                    eprintln!("Accept error: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            },
+            // Reap connections the clients have closed
+            Some(_) = connections.join_next() => {}
+        }
+    }
+    drop(listener);
+    println!(
+        "Server shutting down, {} connections open",
+        connections.len()
+    );
+
+    let mut summary = ShutdownSummary::default();
+    let deadline = tokio::time::Instant::now() + grace + CLOSE_TIMEOUT;
+    loop {
+        match tokio::time::timeout_at(deadline, connections.join_next()).await {
+            Ok(Some(Ok(Some(report)))) => summary.connections.push(report),
+            // Closed by the client before it noticed the shutdown
+            Ok(Some(Ok(None))) => {}
+            Ok(Some(Err(e))) => eprintln!("Connection task failed: {e}"),
+            Ok(None) => break,
+            Err(_) => {
+                summary.aborted = connections.len();
+                connections.shutdown().await;
+                break;
+            }
+        }
+    }
+    summary
+}
+
+/// Serves one connection until the client closes it or the server shuts down.
+///
+/// # Returns
+/// How the connection was drained, or `None` if the client closed it first.
+async fn handle_connection(
+    socket: TcpStream,
+    peer: SocketAddr,
+    commands: Arc<CommandRegistry>,
+    shutdown: CancellationToken,
+    grace: Duration,
+) -> Option<ConnectionReport> {
+    // Split socket into read/write halves, each framed as lines
+    let (reader, writer) = socket.into_split();
+    let mut requests = FramedRead::new(reader, ServerCodec::new());
+    let mut writer = FramedWrite::new(writer, ServerCodec::new());
+
+    // Channel for sending messages to the write half
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Reply>(32);
+
+    // Spawn a task to write messages from the channel
+    let write_task = tokio::spawn(async move {
+        while let Some(reply) = reply_rx.recv().await {
+            println!("Server sending: {reply}");
+            if let Err(e) = writer.send(reply).await {
+                eprintln!("Write error: {e}");
+                break;
+            }
+        }
+    });
+
+    // Long-running commands, cancelled when the connection closes
+    let tasks = Tasks::new(reply_tx.clone());
+
+    // Read loop, until the connection is closed or fails, or the server shuts down
+    let shutting_down = loop {
+        let frame = tokio::select! {
+            _ = shutdown.cancelled() => break true,
+            frame = requests.next() => frame,
+        };
+        let Some(frame) = frame else {
+            break false;
+        };
+        match frame {
+            Ok(Ok(request)) => {
+                println!("Server received: {request}");
+                commands.dispatch(request, &reply_tx, &tasks).await;
+            }
+            // A bad line only costs that line, the connection stays open
+            Ok(Err(e)) => {
+                println!("Server received a bad line: {e}");
+                let reply = Reply {
+                    id: None,
+                    response: Response::Error(e.to_string()),
+                };
+                let _ = reply_tx.send(reply).await;
+            }
+            Err(e) => {
+                eprintln!("Read error: {e}");
+                break false;
+            }
+        }
+    };
+
+    // Let the client know, and give the running tasks the grace period to finish
+    let report = if shutting_down {
+        let notice = Reply {
+            id: None,
+            response: Response::ShuttingDown,
+        };
+        let _ = reply_tx.send(notice).await;
+        let running = tasks.running();
+        let outcome = match tokio::time::timeout(grace, tasks.drain()).await {
+            Ok(()) => DrainOutcome::Drained { finished: running },
+            Err(_) => DrainOutcome::Forced {
+                cancelled: tasks.running(),
+            },
+        };
+        Some(ConnectionReport { peer, outcome })
+    } else {
+        None
+    };
+
+    // Abort whatever is still running. The writer task closes once the
+    // cancelled tasks have replied
+    tasks.cancel_all();
+    drop(tasks);
+    drop(reply_tx);
+    if let Err(e) = write_task.await {
+        eprintln!("Writer task failed: {e}");
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientError, PendingCall};
+    use crate::protocol::Command;
+    use crate::tasks::TaskStatus;
+    use tokio::task::JoinHandle;
+
+    /// Starts a server with a command that sleeps for as long as it's told.
+    async fn start(
+        grace: Duration,
+    ) -> (SocketAddr, CancellationToken, JoinHandle<ShutdownSummary>) {
+        let mut commands = CommandRegistry::new();
+        commands.register(
+            "sleep <ms>",
+            "Sleeps for <ms> milliseconds",
+            |call| async move {
+                let sleep = Duration::from_millis(call.args.parse("ms")?);
+                call.tasks.spawn(call.reply.id(), async move {
+                    tokio::time::sleep(sleep).await;
+                    Response::CalculationComplete
+                })?;
+                Ok(())
+            },
+        );
+        commands.register(
+            "status <id>",
+            "Tells how a sleep is doing",
+            |call| async move {
+                let status = call.tasks.status(call.args.parse("id")?)?;
+                call.reply.send(Response::Status(status)).await;
+                Ok(())
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = server(listener, Arc::new(commands), shutdown.clone(), grace);
+        (addr, shutdown, tokio::spawn(server))
+    }
+
+    /// Sends `sleep <ms>` and waits until the server is running it.
+    async fn sleep(client: &Client, ms: u64) -> PendingCall {
+        let call = client.send(Command::new("sleep").arg(ms)).await.unwrap();
+        // Requests are handled in order, so the task has started once this is answered.
+        let status = client.call(Command::new("status").arg(call.id())).await;
+        assert_eq!(status, Ok(Response::Status(TaskStatus::Running)));
+        call
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_lets_running_tasks_finish() {
+        let (addr, shutdown, server) = start(Duration::from_secs(5)).await;
+        let client = Client::connect(addr).await.unwrap();
+        let calculation = sleep(&client, 50).await;
+
+        shutdown.cancel();
+        assert_eq!(calculation.await, Ok(Response::CalculationComplete));
+        let summary = server.await.unwrap();
+        assert_eq!(summary.connections.len(), 1);
+        assert_eq!(
+            summary.connections[0].outcome,
+            DrainOutcome::Drained { finished: 1 }
+        );
+
+        // The connection closes once it has drained, and nothing more is read.
+        assert_eq!(
+            client.call(Command::new("sleep").arg(1)).await,
+            Err(ClientError::Closed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tasks_are_cancelled_after_the_grace_period() {
+        let (addr, shutdown, server) = start(Duration::from_millis(50)).await;
+        let busy = Client::connect(addr).await.unwrap();
+        let calculation = sleep(&busy, 60_000).await;
+        let idle = Client::connect(addr).await.unwrap();
+        sleep(&idle, 1).await.await.unwrap();
+
+        shutdown.cancel();
+        assert_eq!(calculation.await, Ok(Response::Cancelled));
+        let summary = server.await.unwrap();
+        let mut outcomes: Vec<_> = summary
+            .connections
+            .iter()
+            .map(|report| report.outcome)
+            .collect();
+        outcomes.sort_by_key(|outcome| matches!(outcome, DrainOutcome::Forced { .. }));
+        assert_eq!(
+            outcomes,
+            [
+                DrainOutcome::Drained { finished: 0 },
+                DrainOutcome::Forced { cancelled: 1 }
+            ]
+        );
+        assert_eq!(
+            summary.to_string(),
+            "1 connections drained, 1 forced closed, 0 aborted"
+        );
+    }
+}
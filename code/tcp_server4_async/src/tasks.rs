@@ -0,0 +1,459 @@
+//! The long-running commands in flight on one connection.
+//!
+//! Each task is known by the id of the request that started it, so the client can ask
+//! for its status or cancel it. Whatever happens to a task, exactly one reply goes back
+//! under that id: its result, [`Response::Cancelled`], or an error if it panicked.
+
+use crate::protocol::{Reply, Response};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// How many finished tasks are remembered for [`Tasks::status`].
+const FINISHED_KEPT: usize = 64;
+
+/// The most tasks one connection may have running at once. More are refused with
+/// [`TaskError::TooMany`] until some have ended.
+pub const MAX_RUNNING: usize = 16;
+
+/// Where a task is in its lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskStatus {
+    Running,
+    Completed,
+    /// The task panicked, with the reason why.
+    Failed(String),
+    Cancelled,
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskStatus::Running => write!(f, "running"),
+            TaskStatus::Completed => write!(f, "completed"),
+            TaskStatus::Failed(reason) => write!(f, "failed: {reason}"),
+            TaskStatus::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl FromStr for TaskStatus {
+    type Err = ();
+
+    fn from_str(status: &str) -> Result<Self, ()> {
+        match status {
+            "running" => Ok(TaskStatus::Running),
+            "completed" => Ok(TaskStatus::Completed),
+            "cancelled" => Ok(TaskStatus::Cancelled),
+            status => match status.strip_prefix("failed: ") {
+                Some(reason) => Ok(TaskStatus::Failed(reason.to_string())),
+                None => Err(()),
+            },
+        }
+    }
+}
+
+/// Errors from starting or looking up a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskError {
+    /// A task started by a request with the same id is still running.
+    InUse(u64),
+    /// No task was started by a request with this id, or it finished long ago.
+    NotFound(u64),
+    /// The connection already has [`MAX_RUNNING`] tasks running.
+    TooMany,
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::InUse(id) => write!(f, "a task with request id {id} is still running"),
+            TaskError::NotFound(id) => write!(f, "no task with request id {id}"),
+            TaskError::TooMany => {
+                write!(f, "already running {MAX_RUNNING} tasks, the most allowed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+struct Task {
+    /// Tells this task apart from later ones started under the same request id.
+    run: u64,
+    status: TaskStatus,
+    token: CancellationToken,
+}
+
+#[derive(Default)]
+struct TaskTable {
+    tasks: HashMap<u64, Task>,
+    /// Finished tasks, oldest first, so the oldest can be forgotten.
+    finished: VecDeque<u64>,
+    runs: u64,
+}
+
+impl TaskTable {
+    /// How many tasks are still running. Every task that isn't is in `finished`.
+    fn running(&self) -> usize {
+        self.tasks.len() - self.finished.len()
+    }
+
+    /// Records how the task ended, unless it was cancelled first.
+    ///
+    /// # Arguments
+    /// * `run` - Which task started under `id` ended.
+    /// * `outcome` - What the work returned, or `None` if it was cancelled.
+    ///
+    /// # Returns
+    /// The response to send the client.
+    fn finish(
+        &mut self,
+        id: u64,
+        run: u64,
+        outcome: Option<Result<Response, JoinError>>,
+    ) -> Response {
+        // If the task is gone, or another took its id, the client cancelled it already.
+        let Some(task) = self.tasks.get_mut(&id).filter(|task| task.run == run) else {
+            return Response::Cancelled;
+        };
+        if task.status != TaskStatus::Running {
+            // Cancelled by the client after the work was done but before this ran.
+            return Response::Cancelled;
+        }
+        let (status, response) = match outcome {
+            Some(Ok(response)) => (TaskStatus::Completed, response),
+            Some(Err(err)) => (
+                TaskStatus::Failed(err.to_string()),
+                Response::Error(format!("task failed: {err}")),
+            ),
+            None => (TaskStatus::Cancelled, Response::Cancelled),
+        };
+        task.status = status;
+        self.retire(id);
+        response
+    }
+
+    fn retire(&mut self, id: u64) {
+        self.finished.push_back(id);
+        if self.finished.len() > FINISHED_KEPT
+            && let Some(oldest) = self.finished.pop_front()
+        {
+            self.tasks.remove(&oldest);
+        }
+    }
+}
+
+/// The tasks started by one connection.
+///
+/// Cloning it gives another handle to the same tasks. They keep running until they end
+/// or are cancelled, so the connection must call [`Tasks::cancel_all`] when it closes.
+#[derive(Clone)]
+pub struct Tasks {
+    table: Arc<Mutex<TaskTable>>,
+    /// The parent of every task's token.
+    connection: CancellationToken,
+    tracker: TaskTracker,
+    replies: mpsc::Sender<Reply>,
+}
+
+impl Tasks {
+    /// # Arguments
+    /// * `replies` - Where the reply to each task goes when it ends.
+    pub fn new(replies: mpsc::Sender<Reply>) -> Self {
+        Self {
+            table: Arc::default(),
+            connection: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            replies,
+        }
+    }
+
+    /// Runs `work` for the request `id`, and replies with what it returns.
+    ///
+    /// # Errors
+    /// Returns [`TaskError::InUse`] if the task of an earlier request with the same id is
+    /// still running, or [`TaskError::TooMany`] if [`MAX_RUNNING`] tasks are.
+    pub fn spawn<F>(&self, id: u64, work: F) -> Result<(), TaskError>
+    where
+        F: Future<Output = Response> + Send + 'static,
+    {
+        let token = self.connection.child_token();
+        let run = {
+            let mut table = self.table.lock().unwrap();
+            if let Some(task) = table.tasks.get(&id)
+                && task.status == TaskStatus::Running
+            {
+                return Err(TaskError::InUse(id));
+            }
+            if table.running() >= MAX_RUNNING {
+                return Err(TaskError::TooMany);
+            }
+            table.finished.retain(|finished| *finished != id);
+            table.runs += 1;
+            let task = Task {
+                run: table.runs,
+                status: TaskStatus::Running,
+                token: token.clone(),
+            };
+            table.tasks.insert(id, task);
+            table.runs
+        };
+
+        let mut work = tokio::spawn(work);
+        let table = self.table.clone();
+        let replies = self.replies.clone();
+        self.tracker.spawn(async move {
+            let outcome = tokio::select! {
+                _ = token.cancelled() => {
+                    work.abort();
+                    None
+                }
+                result = &mut work => Some(result),
+            };
+            let response = table.lock().unwrap().finish(id, run, outcome);
+            let _ = replies
+                .send(Reply {
+                    id: Some(id),
+                    response,
+                })
+                .await;
+        });
+        Ok(())
+    }
+
+    /// Cancels the task of request `id`. Its reply is [`Response::Cancelled`].
+    ///
+    /// # Returns
+    /// The task's status afterwards. A task that had already ended keeps the status it
+    /// ended with.
+    ///
+    /// # Errors
+    /// Returns [`TaskError::NotFound`] if there is no such task.
+    pub fn cancel(&self, id: u64) -> Result<TaskStatus, TaskError> {
+        let mut table = self.table.lock().unwrap();
+        let task = table.tasks.get_mut(&id).ok_or(TaskError::NotFound(id))?;
+        if task.status != TaskStatus::Running {
+            return Ok(task.status.clone());
+        }
+        task.status = TaskStatus::Cancelled;
+        task.token.cancel();
+        table.retire(id);
+        Ok(TaskStatus::Cancelled)
+    }
+
+    /// The status of the task of request `id`.
+    ///
+    /// # Errors
+    /// Returns [`TaskError::NotFound`] if there is no such task.
+    pub fn status(&self, id: u64) -> Result<TaskStatus, TaskError> {
+        let table = self.table.lock().unwrap();
+        let task = table.tasks.get(&id).ok_or(TaskError::NotFound(id))?;
+        Ok(task.status.clone())
+    }
+
+    /// How many tasks are still running.
+    pub fn running(&self) -> usize {
+        self.table.lock().unwrap().running()
+    }
+
+    /// Cancels every task still running.
+    pub fn cancel_all(&self) {
+        self.connection.cancel();
+    }
+
+    /// Waits until every task has ended and sent its reply, for a connection that is
+    /// closing and won't start any more.
+    pub async fn drain(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn tasks() -> (Tasks, mpsc::Receiver<Reply>) {
+        let (replies, replies_rx) = mpsc::channel(8);
+        (Tasks::new(replies), replies_rx)
+    }
+
+    fn reply(id: u64, response: Response) -> Option<Reply> {
+        Some(Reply {
+            id: Some(id),
+            response,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_finished_task_replies_with_its_result() {
+        let (tasks, mut replies) = tasks();
+        let (finish, finished) = oneshot::channel();
+        tasks
+            .spawn(1, async move {
+                finished.await.unwrap();
+                Response::CalculationComplete
+            })
+            .unwrap();
+        assert_eq!(tasks.status(1), Ok(TaskStatus::Running));
+
+        finish.send(()).unwrap();
+        assert_eq!(
+            replies.recv().await,
+            reply(1, Response::CalculationComplete)
+        );
+        assert_eq!(tasks.status(1), Ok(TaskStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_task_replies_cancelled() {
+        let (tasks, mut replies) = tasks();
+        tasks.spawn(1, std::future::pending()).unwrap();
+
+        assert_eq!(tasks.cancel(1), Ok(TaskStatus::Cancelled));
+        assert_eq!(replies.recv().await, reply(1, Response::Cancelled));
+        assert_eq!(tasks.status(1), Ok(TaskStatus::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn test_cancelling_a_finished_task_changes_nothing() {
+        let (tasks, mut replies) = tasks();
+        tasks.spawn(1, async { Response::Hello }).unwrap();
+        assert_eq!(replies.recv().await, reply(1, Response::Hello));
+
+        assert_eq!(tasks.cancel(1), Ok(TaskStatus::Completed));
+        assert_eq!(tasks.cancel(2), Err(TaskError::NotFound(2)));
+        assert_eq!(tasks.status(2), Err(TaskError::NotFound(2)));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_is_reported_failed() {
+        let (tasks, mut replies) = tasks();
+        tasks.spawn(1, async { panic!("out of paper") }).unwrap();
+
+        let Some(Reply {
+            id: Some(1),
+            response: Response::Error(message),
+        }) = replies.recv().await
+        else {
+            panic!("expected an error reply");
+        };
+        assert!(message.starts_with("task failed"), "{message}");
+        assert!(matches!(tasks.status(1), Ok(TaskStatus::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ids_of_running_tasks_cannot_be_reused() {
+        let (tasks, mut replies) = tasks();
+        tasks.spawn(1, std::future::pending()).unwrap();
+        assert_eq!(
+            tasks.spawn(1, async { Response::Hello }),
+            Err(TaskError::InUse(1))
+        );
+
+        // Once cancelled, the id is free again straight away.
+        tasks.cancel(1).unwrap();
+        let (finish, finished) = oneshot::channel();
+        tasks
+            .spawn(1, async move {
+                finished.await.unwrap();
+                Response::Hello
+            })
+            .unwrap();
+        assert_eq!(replies.recv().await, reply(1, Response::Cancelled));
+        assert_eq!(tasks.status(1), Ok(TaskStatus::Running));
+
+        finish.send(()).unwrap();
+        assert_eq!(replies.recv().await, reply(1, Response::Hello));
+    }
+
+    #[tokio::test]
+    async fn test_running_tasks_are_limited() {
+        let (tasks, mut replies) = tasks();
+        for id in 0..MAX_RUNNING as u64 {
+            tasks.spawn(id, std::future::pending()).unwrap();
+        }
+        let extra = MAX_RUNNING as u64;
+        assert_eq!(
+            tasks.spawn(extra, async { Response::Hello }),
+            Err(TaskError::TooMany)
+        );
+        assert_eq!(tasks.status(extra), Err(TaskError::NotFound(extra)));
+
+        // Ending one makes room for another.
+        tasks.cancel(0).unwrap();
+        assert_eq!(replies.recv().await, reply(0, Response::Cancelled));
+        tasks.spawn(extra, async { Response::Hello }).unwrap();
+        assert_eq!(replies.recv().await, reply(extra, Response::Hello));
+        assert_eq!(tasks.running(), MAX_RUNNING - 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_cancels_everything_still_running() {
+        let (tasks, mut replies) = tasks();
+        let (_finish, finished) = oneshot::channel::<()>();
+        tasks
+            .spawn(1, async move {
+                let _ = finished.await;
+                Response::CalculationComplete
+            })
+            .unwrap();
+        tasks.spawn(2, std::future::pending()).unwrap();
+        tasks.cancel_all();
+        drop(tasks);
+
+        let mut cancelled = vec![replies.recv().await, replies.recv().await];
+        cancelled.sort_by_key(|reply| reply.as_ref().and_then(|reply| reply.id));
+        assert_eq!(
+            cancelled,
+            [reply(1, Response::Cancelled), reply(2, Response::Cancelled)]
+        );
+        // Every sender is gone once the tasks have finished.
+        assert_eq!(replies.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_running_tasks() {
+        let (tasks, mut replies) = tasks();
+        let (finish, finished) = oneshot::channel();
+        tasks
+            .spawn(1, async move {
+                finished.await.unwrap();
+                Response::CalculationComplete
+            })
+            .unwrap();
+        tasks.spawn(2, async { Response::Hello }).unwrap();
+        assert_eq!(replies.recv().await, reply(2, Response::Hello));
+        assert_eq!(tasks.running(), 1);
+
+        let drain = tasks.drain();
+        tokio::pin!(drain);
+        assert!(futures::poll!(&mut drain).is_pending());
+        finish.send(()).unwrap();
+        drain.await;
+        assert_eq!(tasks.running(), 0);
+        assert_eq!(
+            replies.try_recv().ok(),
+            reply(1, Response::CalculationComplete)
+        );
+    }
+
+    #[test]
+    fn test_status_round_trips() {
+        for status in [
+            TaskStatus::Running,
+            TaskStatus::Completed,
+            TaskStatus::Failed("task 3 panicked".to_string()),
+            TaskStatus::Cancelled,
+        ] {
+            assert_eq!(status.to_string().parse(), Ok(status));
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! An echo server that shuts down cleanly, shared by the `tcp_server_client` examples.
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+/// How long connections get to finish the echo in progress once the server is shutting down.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How long to wait after a failed accept before trying again.
+///
+/// Errors such as running out of file descriptors last until some connections close, so
+/// retrying straight away would only spin.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How the connections open at shutdown were closed.
+#[derive(Debug, Default)]
+pub struct DrainSummary {
+    /// Closed after finishing the echo in progress.
+    pub drained: usize,
+    /// Still writing when the grace period ran out, and aborted.
+    pub forced: usize,
+}
+
+/// Cancels `shutdown` on ctrl-c.
+pub fn cancel_on_ctrl_c(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.cancel();
+        }
+    });
+}
+
+/// Echoes everything each connection sends until `shutdown` is cancelled, then closes
+/// the connections, giving them [`GRACE_PERIOD`] to finish.
+pub async fn server(listener: TcpListener, shutdown: CancellationToken) -> DrainSummary {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => {
+                    connections.spawn(echo(socket, shutdown.clone()));
+                }
+                Err(e) => {
+                    eprintln!("Accept error: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            },
+            // Reap connections the clients have closed
+            Some(_) = connections.join_next() => {}
+        }
+    }
+    drop(listener);
+
+    // Connections notice the shutdown themselves; wait for them to close
+    let mut summary = DrainSummary::default();
+    let deadline = tokio::time::Instant::now() + GRACE_PERIOD;
+    while let Ok(Some(_)) = tokio::time::timeout_at(deadline, connections.join_next()).await {
+        summary.drained += 1;
+    }
+    summary.forced = connections.len();
+    connections.shutdown().await;
+    summary
+}
+
+async fn echo(mut socket: TcpStream, shutdown: CancellationToken) {
+    let mut buf = [0; 1024];
+    loop {
+        let n = tokio::select! {
+            read = socket.read(buf.as_mut()) => match read {
+                Ok(0) | Err(_) => return, // Connection closed
+                Ok(n) => n,
+            },
+            // Tell the client by closing our side of the connection
+            _ = shutdown.cancelled() => {
+                let _ = socket.shutdown().await;
+                return;
+            }
+        };
+        if socket.write_all(&buf[..n]).await.is_err() {
+            return;
+        }
+    }
+}
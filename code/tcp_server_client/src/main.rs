@@ -1,27 +1,24 @@
+use tcp_server_client::{cancel_on_ctrl_c, server};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
-    tokio::spawn(server());
-    tokio::time::sleep(std::time::Duration::from_secs_f32(0.25)).await; // Give the server time to start
+    let listener = TcpListener::bind("127.0.0.1:3001").await.unwrap();
+    let shutdown = CancellationToken::new();
+    cancel_on_ctrl_c(shutdown.clone());
+    let server = tokio::spawn(server(listener, shutdown.clone()));
+
     client().await;
-}
 
-async fn server() {
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3001").await.unwrap();
-    loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
-        tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            while let Ok(n) = socket.read(buf.as_mut()).await {
-                if n == 0 {
-                    return; // Connection closed
-                }
-                socket.write_all(&buf[..n]).await.unwrap();
-            }
-        });
-    }
+    shutdown.cancel();
+    let summary = server.await.unwrap();
+    println!(
+        "Server shut down: {} drained, {} forced",
+        summary.drained, summary.forced
+    );
 }
 
 async fn client() {